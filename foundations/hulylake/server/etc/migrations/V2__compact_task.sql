@@ -0,0 +1,6 @@
+create table compact_task(
+    workspace uuid not null,
+    key text not null,
+
+    primary key (workspace, key)
+)
@@ -0,0 +1,8 @@
+create table key_lock(
+    workspace uuid not null,
+    key text not null,
+    owner text not null,
+    expires timestamptz not null,
+
+    primary key (workspace, key)
+)
\ No newline at end of file
@@ -0,0 +1 @@
+create index object_meta on object using gin ((data -> 'meta'))
\ No newline at end of file
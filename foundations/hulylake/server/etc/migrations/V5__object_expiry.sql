@@ -0,0 +1,9 @@
+create table object_expiry(
+    workspace uuid not null,
+    key text not null,
+    expires timestamptz not null,
+
+    primary key (workspace, key)
+);
+
+create index object_expiry_expires on object_expiry(expires)
\ No newline at end of file
@@ -0,0 +1,9 @@
+create table object_retention(
+    workspace uuid not null,
+    key text not null,
+    prefix bool not null,
+    immutable bool not null,
+    legal_hold bool not null,
+
+    primary key (workspace, key, prefix)
+)
\ No newline at end of file
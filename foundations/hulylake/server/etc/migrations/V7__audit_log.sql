@@ -0,0 +1,17 @@
+create table audit_log(
+    workspace uuid not null,
+    id int8 not null default unique_rowid(),
+    key text not null,
+    action text not null,
+    part int4,
+    etag text,
+    writer text,
+    date timestamptz not null default now(),
+
+    primary key (workspace, id)
+);
+
+-- unique_rowid() is not ordered across nodes, entries are ordered by date
+create index audit_log_date on audit_log(workspace, date desc, id desc);
+
+create index audit_log_key on audit_log(workspace, key, date desc, id desc)
\ No newline at end of file
@@ -0,0 +1,132 @@
+use actix_web::{
+    HttpMessage, HttpRequest, HttpResponse,
+    error::ErrorBadRequest,
+    web::{Data, Path, Query},
+};
+use hulyrs::services::jwt::Claims;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::handlers::{HandlerResult, PartData};
+use crate::postgres::{self, AuditEntry, AuditRecord, Pool};
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display, strum::IntoStaticStr)]
+#[strum(serialize_all = "lowercase")]
+pub enum Action {
+    Put,
+    Patch,
+    Meta,
+    Truncate,
+    Delete,
+}
+
+// account, or the service name for service tokens
+fn identity(claims: &Value) -> Option<String> {
+    match claims.pointer("/extra/service").and_then(Value::as_str) {
+        Some(service) => Some(format!("service:{service}")),
+        None => claims
+            .get("account")
+            .and_then(Value::as_str)
+            .map(str::to_owned),
+    }
+}
+
+/// Identity of the token the request was made with.
+pub fn writer(request: &HttpRequest) -> Option<String> {
+    let claims = request.extensions().get::<Claims>().cloned()?;
+    identity(&serde_json::to_value(claims).ok()?)
+}
+
+/// Audit entry of a mutation, the mutation writes it in its transaction.
+pub fn record<'a>(
+    action: Action,
+    part: Option<&'a PartData>,
+    writer: Option<&'a str>,
+) -> AuditRecord<'a> {
+    AuditRecord {
+        action: action.into(),
+        part: part.map(|part| part.part),
+        etag: part.map(|part| part.etag.as_str()),
+        writer,
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuditQuery {
+    pub key: Option<String>,
+
+    // id of the last entry of the previous page
+    pub before: Option<i64>,
+
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    pub next: Option<i64>,
+}
+
+/// Mutations in the workspace or of a key, the newest first.
+pub async fn list(
+    workspace: Path<Uuid>,
+    query: Query<AuditQuery>,
+    pool: Data<Pool>,
+) -> HandlerResult<HttpResponse> {
+    let query = query.into_inner();
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ErrorBadRequest(format!("invalid limit: {limit}")).into());
+    }
+
+    let mut entries = postgres::find_audit(
+        &pool,
+        workspace.into_inner(),
+        query.key.as_deref(),
+        query.before,
+        limit + 1,
+    )
+    .await?;
+
+    let next = if entries.len() as i64 > limit {
+        entries.truncate(limit as usize);
+        entries.last().map(|entry| entry.id)
+    } else {
+        None
+    };
+
+    Ok(HttpResponse::Ok().json(AuditPage { entries, next }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_identity() {
+        let account = Uuid::new_v4();
+
+        assert_eq!(
+            identity(&json!({ "account": account, "workspace": null })),
+            Some(account.to_string())
+        );
+        assert_eq!(
+            identity(&json!({ "account": account, "extra": { "service": "preview" } })),
+            Some("service:preview".to_string())
+        );
+        assert_eq!(identity(&json!({})), None);
+    }
+
+    #[test]
+    fn test_action() {
+        assert_eq!(Action::Put.to_string(), "put");
+        assert_eq!(Action::Delete.to_string(), "delete");
+        assert_eq!(record(Action::Truncate, None, None).action, "truncate");
+    }
+}
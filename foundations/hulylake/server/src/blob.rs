@@ -3,13 +3,16 @@ use std::error::Error as StdError;
 use aws_sdk_s3::primitives::ByteStream;
 use blake3::Hasher;
 use bytes::{Bytes, BytesMut};
-use futures::stream::StreamExt;
+use futures::stream::{self, StreamExt};
 use futures_util::Stream;
+use serde::{Deserialize, Serialize};
 use size::Size;
 use tokio_postgres::error::SqlState;
 use tracing::*;
 
+use crate::chunk;
 use crate::handlers::ApiError;
+use crate::metrics;
 use crate::postgres::DbError;
 use crate::recovery;
 use crate::s3::S3Client;
@@ -20,103 +23,214 @@ use crate::{
 
 #[derive(Debug)]
 pub struct Blob {
-    pub s3_key: String,
+    // s3 object holding the content, none for chunked and inline only blobs
+    pub s3_key: Option<String>,
     pub hash: String,
     pub length: usize,
     pub inline: Option<Bytes>,
     pub parts_count: Option<usize>,
     pub deduplicated: bool,
+
+    // too small for s3, kept only in the database
+    pub inline_only: bool,
+
+    // set if the blob is stored as content-defined chunks
+    pub chunks: Option<Vec<Chunk>>,
+}
+
+impl Blob {
+    /// Content kept in the database, small blobs are read without a round trip to s3.
+    pub fn stored_inline(&self) -> Option<Bytes> {
+        self.inline.clone().filter(|inline| {
+            self.inline_only || inline.len() < CONFIG.inline_threshold.bytes() as usize
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub blob: String,
+    pub size: usize,
 }
 
 fn random_key() -> String {
     ksuid::Ksuid::generate().to_base62()
 }
 
-#[instrument(level = "debug", skip_all, fields(s3_bucket))]
 pub async fn upload<S, E>(
     s3: &S3Client,
     pool: &Pool,
-    length: Size,
+    length: Option<Size>,
     mut source: S,
 ) -> Result<Blob, ApiError>
 where
     S: Stream<Item = Result<Bytes, E>> + Unpin,
     E: StdError + Send + Sync + 'static,
 {
-    let span = Span::current();
+    let threshold = CONFIG.multipart_threshold.bytes() as usize;
 
-    let s3_bucket = &CONFIG.s3_bucket;
-    span.record("s3_bucket", &s3_bucket);
+    let blob = match length {
+        Some(length) if length < CONFIG.multipart_threshold => {
+            let buffer = read_exact(&mut source, length).await?;
+            upload_buffer(s3, pool, buffer).await?
+        }
 
-    let blob = if length < CONFIG.multipart_threshold {
-        let mut hash = Hasher::new();
+        Some(_) => upload_stream(s3, pool, source).await?,
 
-        let mut buffer = BytesMut::new();
+        // the size is unknown, the beginning of the body decides between inline and multipart
+        None => {
+            let buffer = read_up_to(&mut source, threshold).await?;
 
-        // read in all chunks, but not more that length
-        while let Some(Ok(chunk)) = source.next().await {
-            buffer.extend_from_slice(&chunk);
+            if buffer.len() < threshold {
+                upload_buffer(s3, pool, buffer).await?
+            } else {
+                let source = stream::iter([Ok(buffer)]).chain(source);
+                upload_stream(s3, pool, source).await?
+            }
+        }
+    };
 
-            if buffer.len() > length.bytes() as usize {
+    if !blob.deduplicated
+        && let Some(s3_key) = &blob.s3_key
+    {
+        recovery::set_blob(s3, s3_key, &blob.hash).await?;
+    }
+
+    Ok(blob)
+}
+
+/// Reads the body in memory whatever the size, for json bodies which are parsed on write, before
+/// anything is stored.
+pub async fn buffer<S, E>(length: Option<Size>, mut source: S) -> Result<Bytes, ApiError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: StdError + Send + Sync + 'static,
+{
+    let limit = CONFIG.json_size_limit.bytes() as usize;
+
+    match length {
+        // refused before reading, whatever the client declares would be allocated otherwise
+        Some(length) if length.bytes() as usize > limit => {
+            Err(actix_web::error::ErrorPayloadTooLarge("payload too large").into())
+        }
+        Some(length) => read_exact(&mut source, length).await,
+        None => {
+            let buffer = read_up_to(&mut source, limit + 1).await?;
+
+            if buffer.len() > limit {
                 return Err(actix_web::error::ErrorPayloadTooLarge("payload too large").into());
             }
-        }
 
-        if buffer.len() != length.bytes() as usize {
-            return Err(actix_web::error::ErrorBadRequest("payload size mismatch").into());
+            Ok(buffer)
         }
+    }
+}
 
-        let buffer = buffer.freeze();
+/// Stores a body read by buffer.
+pub async fn upload_buffered(s3: &S3Client, pool: &Pool, buffer: Bytes) -> Result<Blob, ApiError> {
+    let blob = upload_buffer(s3, pool, buffer).await?;
 
-        let hash = hash.update(&buffer).finalize().to_hex().to_string();
-        let length = buffer.len();
+    if !blob.deduplicated
+        && let Some(s3_key) = &blob.s3_key
+    {
+        recovery::set_blob(s3, s3_key, &blob.hash).await?;
+    }
 
-        let inline = Some(buffer.clone());
+    Ok(blob)
+}
 
-        let (s3_key, deduplicated) =
-            if let Some(s3_key_found) = postgres::find_blob_by_hash(&pool, &hash).await? {
-                span.record("s3_key", &s3_key_found);
-                debug!(s3_key_found, "blob deduplicated");
-                (s3_key_found, true)
-            } else {
-                let s3_key = random_key();
-                span.record("s3_key", &s3_key);
+// reads in all chunks, but not more than length
+async fn read_exact<S, E>(source: &mut S, length: Size) -> Result<Bytes, ApiError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    let mut buffer = BytesMut::new();
 
-                s3.put_object()
-                    .bucket(s3_bucket)
-                    .key(&s3_key)
-                    .body(ByteStream::from(buffer))
-                    .send()
-                    .await?;
+    while let Some(Ok(chunk)) = source.next().await {
+        buffer.extend_from_slice(&chunk);
 
-                match make_blob(pool, &s3_key, &hash).await? {
-                    Some(s3_key_found) => {
-                        debug!(s3_key_found, "blob deduplicated");
+        if buffer.len() > length.bytes() as usize {
+            return Err(actix_web::error::ErrorPayloadTooLarge("payload too large").into());
+        }
+    }
 
-                        // delete uploaded
-                        s3.delete_object()
-                            .bucket(s3_bucket)
-                            .key(&s3_key)
-                            .send()
-                            .await?;
+    if buffer.len() != length.bytes() as usize {
+        return Err(actix_web::error::ErrorBadRequest("payload size mismatch").into());
+    }
 
-                        (s3_key_found, true)
-                    }
-                    None => {
-                        debug!("blob created");
-                        (s3_key, false)
-                    }
-                }
-            };
+    Ok(buffer.freeze())
+}
+
+// reads chunks until the end of the body or until there are at least limit bytes,
+// the body has no declared size, so a broken stream is an error rather than a short body
+async fn read_up_to<S, E>(source: &mut S, limit: usize) -> Result<Bytes, ApiError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: StdError + Send + Sync + 'static,
+{
+    let mut buffer = BytesMut::new();
+
+    while buffer.len() < limit
+        && let Some(chunk) = source.next().await
+    {
+        let chunk = chunk.map_err(|e| ApiError::Other(e.into()))?;
+        buffer.extend_from_slice(&chunk);
+    }
 
-        Blob {
+    Ok(buffer.freeze())
+}
+
+#[instrument(level = "debug", skip_all, fields(s3_key))]
+async fn upload_buffer(s3: &S3Client, pool: &Pool, buffer: Bytes) -> Result<Blob, ApiError> {
+    let hash = blake3::hash(&buffer).to_hex().to_string();
+    let length = buffer.len();
+
+    let inline = Some(buffer.clone());
+
+    // a tiny blob is not worth an s3 round trip, unless it is there already
+    if length < CONFIG.inline_only_threshold.bytes() as usize
+        && postgres::find_blob_by_hash(pool, &hash).await?.is_none()
+    {
+        return Ok(Blob {
+            s3_key: None,
             hash,
-            s3_key,
             length,
             inline,
             parts_count: None,
-            deduplicated,
-        }
+            deduplicated: false,
+            inline_only: true,
+            chunks: None,
+        });
+    }
+
+    let (s3_key, deduplicated) = store(s3, pool, buffer, &hash).await?;
+    Span::current().record("s3_key", &s3_key);
+
+    Ok(Blob {
+        hash,
+        s3_key: Some(s3_key),
+        length,
+        inline,
+        parts_count: None,
+        deduplicated,
+        inline_only: false,
+        chunks: None,
+    })
+}
+
+#[instrument(level = "debug", skip_all, fields(s3_bucket, s3_key))]
+async fn upload_stream<S, E>(s3: &S3Client, pool: &Pool, source: S) -> Result<Blob, ApiError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: StdError + Send + Sync + 'static,
+{
+    let span = Span::current();
+
+    let s3_bucket = &CONFIG.s3_bucket;
+    span.record("s3_bucket", &s3_bucket);
+
+    if CONFIG.chunking {
+        upload_chunked(s3, pool, source).await
     } else {
         let s3_key = random_key();
         span.record("s3_key", &s3_key);
@@ -136,11 +250,11 @@ where
                         debug!(s3_key_found, "blob deduplicated");
 
                         // delete uploaded
-                        s3.delete_object()
-                            .bucket(s3_bucket)
-                            .key(&s3_key)
-                            .send()
-                            .await?;
+                        metrics::s3(
+                            "delete_object",
+                            s3.delete_object().bucket(s3_bucket).key(&s3_key).send(),
+                        )
+                        .await?;
 
                         (s3_key_found, true)
                     }
@@ -152,21 +266,117 @@ where
             }
         };
 
-        Blob {
+        Ok(Blob {
             hash,
-            s3_key,
+            s3_key: Some(s3_key),
             length: upload.length,
             inline: None,
             parts_count: Some(upload.parts_count),
             deduplicated,
+            inline_only: false,
+            chunks: None,
+        })
+    }
+}
+
+// store buffer as a new blob, unless a blob with the same hash exists
+async fn store(
+    s3: &S3Client,
+    pool: &Pool,
+    buffer: Bytes,
+    hash: &str,
+) -> Result<(String, bool), ApiError> {
+    let s3_bucket = &CONFIG.s3_bucket;
+
+    if let Some(s3_key_found) = postgres::find_blob_by_hash(pool, hash).await? {
+        debug!(s3_key_found, "blob deduplicated");
+        return Ok((s3_key_found, true));
+    }
+
+    let s3_key = random_key();
+
+    metrics::s3(
+        "put_object",
+        s3.put_object()
+            .bucket(s3_bucket)
+            .key(&s3_key)
+            .body(ByteStream::from(buffer))
+            .send(),
+    )
+    .await?;
+
+    match make_blob(pool, &s3_key, hash).await? {
+        Some(s3_key_found) => {
+            debug!(s3_key_found, "blob deduplicated");
+
+            // delete uploaded
+            metrics::s3(
+                "delete_object",
+                s3.delete_object().bucket(s3_bucket).key(&s3_key).send(),
+            )
+            .await?;
+
+            Ok((s3_key_found, true))
         }
-    };
+        None => {
+            debug!("blob created");
+            Ok((s3_key, false))
+        }
+    }
+}
+
+#[instrument(level = "debug", skip_all)]
+async fn upload_chunked<S, E>(s3: &S3Client, pool: &Pool, source: S) -> Result<Blob, ApiError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: StdError + Send + Sync + 'static,
+{
+    let mut source = std::pin::pin!(chunk::chunks(source, CONFIG.chunk_size.bytes() as usize));
+
+    let mut hash = Hasher::new();
+    let mut length = 0;
+    let mut chunks = Vec::new();
+    let mut deduplicated = true;
 
-    if !blob.deduplicated {
-        recovery::set_blob(&s3, &blob.s3_key, &blob.hash).await?;
+    while let Some(buffer) = source.next().await {
+        let buffer = buffer.map_err(|e| ApiError::Other(e.into()))?;
+
+        hash.update(&buffer);
+        length += buffer.len();
+
+        let size = buffer.len();
+        let chunk_hash = blake3::hash(&buffer).to_hex().to_string();
+
+        let (s3_key, chunk_deduplicated) = store(s3, pool, buffer, &chunk_hash).await?;
+
+        if !chunk_deduplicated {
+            recovery::set_blob(s3, &s3_key, &chunk_hash).await?;
+        }
+
+        deduplicated &= chunk_deduplicated;
+        chunks.push(Chunk { blob: s3_key, size });
     }
 
-    Ok(blob)
+    let hash = hash.finalize().to_hex().to_string();
+
+    debug!(
+        length,
+        chunks = chunks.len(),
+        deduplicated,
+        "chunked upload complete"
+    );
+
+    Ok(Blob {
+        // chunked blobs have no s3 object of their own
+        s3_key: None,
+        hash,
+        length,
+        inline: None,
+        parts_count: None,
+        deduplicated,
+        inline_only: false,
+        chunks: Some(chunks),
+    })
 }
 
 async fn make_blob(pool: &Pool, s3_key: &String, hash: &str) -> Result<Option<String>, DbError> {
@@ -196,3 +406,94 @@ async fn make_blob(pool: &Pool, s3_key: &String, hash: &str) -> Result<Option<St
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{ResponseError, http::StatusCode};
+
+    fn body(chunks: &[&'static str]) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Unpin {
+        stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes())))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn test_stored_inline() {
+        let mut blob = Blob {
+            s3_key: None,
+            hash: "hash".to_string(),
+            length: 5,
+            inline: Some(Bytes::from_static(b"hello")),
+            parts_count: None,
+            deduplicated: false,
+            inline_only: true,
+            chunks: None,
+        };
+        assert!(blob.stored_inline().is_some());
+
+        // larger than the inline threshold, kept in s3 only
+        blob.inline_only = false;
+        blob.inline = Some(Bytes::from(vec![
+            0;
+            CONFIG.inline_threshold.bytes() as usize
+        ]));
+        assert_eq!(blob.stored_inline(), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_up_to() {
+        // stops at the first chunk reaching the limit, the rest stays in the stream
+        let mut source = body(&["abc", "def", "gh"]);
+        assert_eq!(read_up_to(&mut source, 4).await.unwrap(), "abcdef");
+        assert_eq!(read_up_to(&mut source, 4).await.unwrap(), "gh");
+
+        let mut source = body(&[]);
+        assert_eq!(read_up_to(&mut source, 4).await.unwrap(), "");
+
+        let mut source = stream::iter([
+            Ok(Bytes::from_static(b"abc")),
+            Err(std::io::Error::other("reset")),
+        ]);
+        assert!(read_up_to(&mut source, 10).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_exact() {
+        let mut source = body(&["abc", "def"]);
+        assert_eq!(
+            read_exact(&mut source, Size::from_bytes(6)).await.unwrap(),
+            "abcdef"
+        );
+
+        let mut source = body(&["abc", "def"]);
+        assert!(read_exact(&mut source, Size::from_bytes(4)).await.is_err());
+
+        let mut source = body(&["abc"]);
+        assert!(read_exact(&mut source, Size::from_bytes(4)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_buffer() {
+        let limit = CONFIG.json_size_limit.bytes();
+
+        let error = buffer(Some(Size::from_bytes(limit + 1)), body(&["{}"]))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.error_response().status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+
+        assert_eq!(
+            buffer(Some(Size::from_bytes(2)), body(&["{}"]))
+                .await
+                .unwrap(),
+            "{}"
+        );
+        assert_eq!(buffer(None, body(&["{", "}"])).await.unwrap(), "{}");
+    }
+}
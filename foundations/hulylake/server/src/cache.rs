@@ -0,0 +1,720 @@
+use std::io::{Error as IoError, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_stream::stream;
+use bytes::{Bytes, BytesMut};
+use futures_util::Stream;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::*;
+use uuid::Uuid;
+
+use crate::config::CONFIG;
+use crate::handlers::PartData;
+use crate::metrics;
+use crate::postgres::ObjectPart;
+
+#[derive(Hash, PartialEq, Eq, Debug, Clone)]
+struct CacheKey {
+    workspace: Uuid,
+    key: String,
+    etag: String,
+}
+
+// disk tier keeps the latest document of each key
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+    etag: String,
+    document: Value,
+}
+
+/// Materialized json documents, keyed by the etag of the last part they include.
+pub struct DocumentCache {
+    memory: Option<Mutex<DocumentMemory>>,
+    disk: Option<DocumentDisk>,
+}
+
+// memory tier, bounded by the serialized size of its documents
+struct DocumentMemory {
+    entries: LruCache<CacheKey, (Arc<Value>, u64)>,
+    size: u64,
+    capacity: u64,
+}
+
+impl DocumentMemory {
+    fn insert(&mut self, key: CacheKey, document: Arc<Value>, size: u64) {
+        if let Some((_, previous)) = self.entries.put(key, (document, size)) {
+            self.size -= previous;
+        }
+        self.size += size;
+
+        while self.size > self.capacity {
+            let Some((_, (_, size))) = self.entries.pop_lru() else {
+                break;
+            };
+            self.size -= size;
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some((_, size)) = self.entries.pop(key) {
+            self.size -= size;
+        }
+    }
+}
+
+// disk tier, bounded by the size of its files
+struct DocumentDisk {
+    dir: PathBuf,
+    capacity: u64,
+    index: Mutex<FileIndex>,
+}
+
+impl DocumentCache {
+    /// Picks up disk entries left by a previous instance.
+    pub fn new(memory: u64, disk: Option<(PathBuf, u64)>) -> std::io::Result<Self> {
+        let disk = match disk {
+            Some((dir, capacity)) => {
+                let index = FileIndex::load(&dir)?;
+                let disk = DocumentDisk {
+                    dir,
+                    capacity,
+                    index: Mutex::new(index),
+                };
+                disk.index.lock().unwrap().evict(&disk.dir, disk.capacity);
+
+                Some(disk)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            memory: (memory > 0).then(|| {
+                Mutex::new(DocumentMemory {
+                    entries: LruCache::unbounded(),
+                    size: 0,
+                    capacity: memory,
+                })
+            }),
+            disk,
+        })
+    }
+
+    pub fn from_config() -> std::io::Result<Self> {
+        let disk = CONFIG.document_cache_dir.as_ref().map(|dir| {
+            (
+                PathBuf::from(dir),
+                CONFIG.document_cache_disk_size.bytes() as u64,
+            )
+        });
+
+        Self::new(CONFIG.document_cache_size.bytes() as u64, disk)
+    }
+
+    fn disk_name(workspace: Uuid, key: &str) -> String {
+        let hash = blake3::hash(format!("{workspace}/{key}").as_bytes());
+        format!("{}.json", hash.to_hex())
+    }
+
+    /// Cached document for the longest prefix of parts, with the number of parts it includes.
+    pub async fn find(&self, parts: &[ObjectPart<PartData>]) -> Option<(Value, usize)> {
+        let last = &parts.last()?.data;
+
+        if let Some(memory) = &self.memory {
+            let mut memory = memory.lock().unwrap();
+
+            for (i, part) in parts.iter().enumerate().rev() {
+                let key = CacheKey {
+                    workspace: last.workspace,
+                    key: last.key.clone(),
+                    etag: part.data.etag.clone(),
+                };
+
+                if let Some((document, _)) = memory.entries.get(&key) {
+                    metrics::DOCUMENT_CACHE.with_label_values(&["memory"]).inc();
+                    return Some((document.as_ref().clone(), i + 1));
+                }
+            }
+        }
+
+        if let Some(disk) = &self.disk {
+            let name = Self::disk_name(last.workspace, &last.key);
+            let path = disk.dir.join(&name);
+
+            // touched, so that read entries are evicted last
+            let cached = disk.index.lock().unwrap().entries.get(&name).is_some();
+
+            if cached && let Ok(bytes) = tokio::fs::read(&path).await {
+                match serde_json::from_slice::<DiskEntry>(&bytes) {
+                    Ok(entry) => {
+                        if let Some(i) = parts.iter().position(|p| p.data.etag == entry.etag) {
+                            metrics::DOCUMENT_CACHE.with_label_values(&["disk"]).inc();
+                            return Some((entry.document, i + 1));
+                        }
+                    }
+                    Err(error) => warn!(%error, ?path, "invalid document cache entry"),
+                }
+            }
+        }
+
+        metrics::DOCUMENT_CACHE.with_label_values(&["miss"]).inc();
+        None
+    }
+
+    pub async fn insert(&self, workspace: Uuid, key: &str, etag: &str, document: &Value) {
+        if let Some(memory) = &self.memory {
+            let size = serde_json::to_vec(document).map_or(u64::MAX, |bytes| bytes.len() as u64);
+
+            // large documents would push out many small ones, they are read from disk instead
+            if size <= CONFIG.inline_threshold.bytes() as u64 {
+                let key = CacheKey {
+                    workspace,
+                    key: key.to_owned(),
+                    etag: etag.to_owned(),
+                };
+
+                memory
+                    .lock()
+                    .unwrap()
+                    .insert(key, Arc::new(document.clone()), size);
+            }
+        }
+
+        if let Some(disk) = &self.disk {
+            let name = Self::disk_name(workspace, key);
+            let path = disk.dir.join(&name);
+
+            let entry = DiskEntry {
+                etag: etag.to_owned(),
+                document: document.clone(),
+            };
+
+            let result = match serde_json::to_vec(&entry) {
+                Ok(bytes) => {
+                    // write aside and rename, so that readers never see a partial file
+                    let size = bytes.len() as u64;
+                    let temp = path.with_extension("tmp");
+                    match tokio::fs::write(&temp, bytes).await {
+                        Ok(_) => tokio::fs::rename(&temp, &path).await.map(|_| size),
+                        Err(error) => Err(error),
+                    }
+                }
+                Err(error) => Err(error.into()),
+            };
+
+            match result {
+                Ok(size) => {
+                    let mut index = disk.index.lock().unwrap();
+                    index.insert(name, size);
+                    index.evict(&disk.dir, disk.capacity);
+                }
+                Err(error) => warn!(%error, ?path, "failed to write document cache entry"),
+            }
+        }
+    }
+
+    /// Drops the documents of a deleted or truncated object.
+    pub async fn remove(&self, workspace: Uuid, key: &str) {
+        if let Some(memory) = &self.memory {
+            let mut memory = memory.lock().unwrap();
+
+            let keys = memory
+                .entries
+                .iter()
+                .map(|(cached, _)| cached)
+                .filter(|cached| cached.workspace == workspace && cached.key == key)
+                .cloned()
+                .collect::<Vec<_>>();
+
+            for cached in keys {
+                memory.remove(&cached);
+            }
+        }
+
+        if let Some(disk) = &self.disk {
+            let name = Self::disk_name(workspace, key);
+
+            if disk.index.lock().unwrap().remove(&name).is_some()
+                && let Err(error) = tokio::fs::remove_file(disk.dir.join(&name)).await
+            {
+                warn!(%error, name, "failed to remove document cache entry");
+            }
+        }
+    }
+}
+
+// files of a disk cache, the least recently used first
+struct FileIndex {
+    entries: LruCache<String, u64>,
+    size: u64,
+}
+
+impl FileIndex {
+    // picks up files left by a previous instance, the least recently modified are evicted first
+    fn load(dir: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            // an interrupted write
+            if name.ends_with(".tmp") {
+                std::fs::remove_file(entry.path())?;
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            files.push((metadata.modified()?, name, metadata.len()));
+        }
+        files.sort();
+
+        let mut index = FileIndex {
+            entries: LruCache::unbounded(),
+            size: 0,
+        };
+        for (_, name, size) in files {
+            index.insert(name, size);
+        }
+
+        Ok(index)
+    }
+
+    fn insert(&mut self, name: String, size: u64) {
+        if let Some(previous) = self.entries.put(name, size) {
+            self.size -= previous;
+        }
+        self.size += size;
+    }
+
+    fn remove(&mut self, name: &str) -> Option<u64> {
+        let size = self.entries.pop(name)?;
+        self.size -= size;
+        Some(size)
+    }
+
+    // removes the least recently used files until the index fits the capacity
+    fn evict(&mut self, dir: &Path, capacity: u64) {
+        while self.size > capacity {
+            let Some((name, size)) = self.entries.pop_lru() else {
+                break;
+            };
+            self.size -= size;
+
+            // open readers keep the content
+            if let Err(error) = std::fs::remove_file(dir.join(&name)) {
+                warn!(%error, name, "failed to remove cached file");
+            }
+        }
+    }
+}
+
+// read size of cached files
+const READ_BUFFER: usize = 64 * 1024;
+
+/// S3 blobs on local disk, blobs never change once written so entries are only evicted.
+pub struct BlobCache {
+    dir: PathBuf,
+    capacity: u64,
+    index: Mutex<FileIndex>,
+}
+
+impl BlobCache {
+    /// Picks up files left by a previous instance, the least recently modified are evicted first.
+    pub fn new(dir: PathBuf, capacity: u64) -> std::io::Result<Self> {
+        let index = FileIndex::load(&dir)?;
+
+        let cache = Self {
+            dir,
+            capacity,
+            index: Mutex::new(index),
+        };
+        cache.evict();
+
+        Ok(cache)
+    }
+
+    /// The blob cache, if configured.
+    pub fn from_config() -> std::io::Result<Option<Self>> {
+        let Some(dir) = &CONFIG.blob_cache_dir else {
+            return Ok(None);
+        };
+
+        let cache = Self::new(PathBuf::from(dir), CONFIG.blob_cache_size.bytes() as u64)?;
+        info!(dir, size = cache.size(), "blob cache");
+
+        Ok(Some(cache))
+    }
+
+    fn name(blob: &str) -> String {
+        blake3::hash(blob.as_bytes()).to_hex().to_string()
+    }
+
+    fn size(&self) -> u64 {
+        self.index.lock().unwrap().size
+    }
+
+    // removes the least recently read blobs until the cache fits its capacity
+    fn evict(&self) {
+        let mut index = self.index.lock().unwrap();
+        index.evict(&self.dir, self.capacity);
+
+        metrics::BLOB_CACHE_SIZE.set(index.size as i64);
+    }
+
+    /// Content of a cached blob from the byte at from to the byte at to, inclusive.
+    pub async fn read(
+        &self,
+        blob: &str,
+        from: u64,
+        to: Option<u64>,
+    ) -> Option<impl Stream<Item = Result<Bytes, IoError>> + Send + use<>> {
+        let name = Self::name(blob);
+
+        let size = self.index.lock().unwrap().entries.get(&name).copied();
+        let Some(size) = size else {
+            metrics::BLOB_CACHE.with_label_values(&["miss"]).inc();
+            return None;
+        };
+
+        let mut file = match tokio::fs::File::open(self.dir.join(&name)).await {
+            Ok(file) => file,
+            Err(error) => {
+                // removed behind our back
+                warn!(%error, name, "cached blob is missing");
+                self.index.lock().unwrap().remove(&name);
+                metrics::BLOB_CACHE.with_label_values(&["miss"]).inc();
+                return None;
+            }
+        };
+
+        metrics::BLOB_CACHE.with_label_values(&["hit"]).inc();
+
+        let end = to.map_or(size, |to| (to + 1).min(size));
+
+        Some(stream! {
+            if let Err(error) = file.seek(SeekFrom::Start(from)).await {
+                yield Err(error);
+                return;
+            }
+
+            let mut remaining = end.saturating_sub(from);
+
+            while remaining > 0 {
+                let mut buffer = BytesMut::zeroed(READ_BUFFER.min(remaining as usize));
+
+                match file.read(&mut buffer).await {
+                    Ok(0) => {
+                        yield Err(IoError::from(std::io::ErrorKind::UnexpectedEof));
+                        return;
+                    }
+                    Ok(read) => {
+                        buffer.truncate(read);
+                        remaining -= read as u64;
+                        yield Ok(buffer.freeze());
+                    }
+                    Err(error) => {
+                        yield Err(error);
+                        return;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Writer of a blob read from s3, large blobs are not cached so that one read
+    /// does not flush the whole cache.
+    pub async fn writer(self: &Arc<Self>, blob: &str, size: u64) -> Option<BlobWriter> {
+        if size > self.capacity / 8 {
+            return None;
+        }
+
+        let name = Self::name(blob);
+        if self.index.lock().unwrap().entries.contains(&name) {
+            return None;
+        }
+
+        // concurrent readers of the same blob write their own temp files
+        let temp = self
+            .dir
+            .join(format!("{name}.{}.tmp", Uuid::new_v4().simple()));
+
+        match tokio::fs::File::create(&temp).await {
+            Ok(file) => Some(BlobWriter {
+                cache: self.clone(),
+                name,
+                temp,
+                file: Some(file),
+                size,
+                written: 0,
+            }),
+            Err(error) => {
+                warn!(%error, "failed to create cached blob");
+                None
+            }
+        }
+    }
+
+    fn insert(&self, name: String, size: u64) {
+        self.index.lock().unwrap().insert(name, size);
+        self.evict();
+    }
+}
+
+/// Blob content being written to the cache, the entry is added once the whole blob is written.
+pub struct BlobWriter {
+    cache: Arc<BlobCache>,
+    name: String,
+    temp: PathBuf,
+    file: Option<tokio::fs::File>,
+    size: u64,
+    written: u64,
+}
+
+impl BlobWriter {
+    pub async fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        if let Some(file) = &mut self.file {
+            file.write_all(bytes).await?;
+            self.written += bytes.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    pub async fn commit(mut self) {
+        let Some(mut file) = self.file.take() else {
+            return;
+        };
+
+        if self.written != self.size {
+            warn!(
+                self.name,
+                self.size, self.written, "cached blob size mismatch"
+            );
+            return;
+        }
+
+        let path = self.cache.dir.join(&self.name);
+        let result = match file.flush().await {
+            Ok(_) => tokio::fs::rename(&self.temp, &path).await,
+            Err(error) => Err(error),
+        };
+
+        match result {
+            Ok(_) => self.cache.insert(std::mem::take(&mut self.name), self.size),
+            Err(error) => warn!(%error, ?path, "failed to write cached blob"),
+        }
+    }
+}
+
+impl Drop for BlobWriter {
+    fn drop(&mut self) {
+        // not committed, the read was interrupted or the content did not match
+        if self.temp.exists() {
+            let _ = std::fs::remove_file(&self.temp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn object_part(etag: &str) -> ObjectPart<PartData> {
+        ObjectPart {
+            inline: None,
+            data: PartData {
+                workspace: Uuid::nil(),
+                key: "test".to_string(),
+                part: 0,
+                size: 0,
+                blob: Some("test".to_string()),
+                etag: etag.to_owned(),
+                date: chrono::Utc::now(),
+                headers: None,
+                meta: None,
+                merge_strategy: None,
+                chunks: None,
+                segments: None,
+                writer: None,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_find_prefix() {
+        let cache = DocumentCache::new(1024, None).unwrap();
+        let parts = vec![object_part("a"), object_part("b"), object_part("c")];
+
+        assert_eq!(cache.find(&parts).await, None);
+
+        cache
+            .insert(Uuid::nil(), "test", "b", &json!({ "b": 1 }))
+            .await;
+        assert_eq!(cache.find(&parts).await, Some((json!({ "b": 1 }), 2)));
+
+        cache
+            .insert(Uuid::nil(), "test", "c", &json!({ "c": 1 }))
+            .await;
+        assert_eq!(cache.find(&parts).await, Some((json!({ "c": 1 }), 3)));
+
+        // another key
+        cache.insert(Uuid::nil(), "other", "c", &json!({})).await;
+        assert_eq!(cache.find(&parts[..1]).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_eviction() {
+        let cache = DocumentCache::new(1, None).unwrap();
+        let parts = vec![object_part("a"), object_part("b")];
+
+        cache.insert(Uuid::nil(), "test", "a", &json!(1)).await;
+        cache.insert(Uuid::nil(), "test", "x", &json!(2)).await;
+
+        assert_eq!(cache.find(&parts).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_large_document() {
+        let cache = DocumentCache::new(u64::MAX, None).unwrap();
+        let parts = vec![object_part("a")];
+
+        let text = "a".repeat(CONFIG.inline_threshold.bytes() as usize);
+        cache.insert(Uuid::nil(), "test", "a", &json!(text)).await;
+
+        assert_eq!(cache.find(&parts).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_disk() {
+        let dir = std::env::temp_dir().join(format!("hulylake-cache-{}", Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+
+        let parts = vec![object_part("a"), object_part("b")];
+
+        DocumentCache::new(1024, Some((dir.clone(), 1024)))
+            .unwrap()
+            .insert(Uuid::nil(), "test", "a", &json!({ "a": 1 }))
+            .await;
+
+        // memory is empty in a new instance
+        let cache = DocumentCache::new(1024, Some((dir.clone(), 1024))).unwrap();
+        assert_eq!(cache.find(&parts).await, Some((json!({ "a": 1 }), 1)));
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disk_eviction() {
+        let dir = std::env::temp_dir().join(format!("hulylake-cache-{}", Uuid::new_v4()));
+
+        // room for one entry
+        let cache = DocumentCache::new(0, Some((dir.clone(), 40))).unwrap();
+
+        cache.insert(Uuid::nil(), "test", "a", &json!(1)).await;
+        cache.insert(Uuid::nil(), "other", "b", &json!(2)).await;
+
+        assert_eq!(cache.find(&[object_part("a")]).await, None);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remove() {
+        let dir = std::env::temp_dir().join(format!("hulylake-cache-{}", Uuid::new_v4()));
+
+        let cache = DocumentCache::new(1024, Some((dir.clone(), 1024))).unwrap();
+        let parts = vec![object_part("a"), object_part("b")];
+
+        cache.insert(Uuid::nil(), "test", "a", &json!(1)).await;
+        cache.insert(Uuid::nil(), "test", "b", &json!(2)).await;
+        cache.insert(Uuid::nil(), "other", "b", &json!(3)).await;
+
+        cache.remove(Uuid::nil(), "test").await;
+        assert_eq!(cache.find(&parts).await, None);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    async fn read_all(
+        cache: &BlobCache,
+        blob: &str,
+        from: u64,
+        to: Option<u64>,
+    ) -> Option<Vec<u8>> {
+        let stream = cache.read(blob, from, to).await?;
+        let mut stream = std::pin::pin!(stream);
+
+        let mut bytes = Vec::new();
+        while let Some(chunk) = futures::StreamExt::next(&mut stream).await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+
+        Some(bytes)
+    }
+
+    async fn write(cache: &Arc<BlobCache>, blob: &str, content: &[u8]) {
+        let mut writer = cache.writer(blob, content.len() as u64).await.unwrap();
+        writer.write(content).await.unwrap();
+        writer.commit().await;
+    }
+
+    #[tokio::test]
+    async fn test_blob_cache() {
+        let dir = std::env::temp_dir().join(format!("hulylake-blobs-{}", Uuid::new_v4()));
+
+        let cache = Arc::new(BlobCache::new(dir.clone(), 80).unwrap());
+        assert_eq!(read_all(&cache, "a", 0, None).await, None);
+
+        write(&cache, "a", b"0123456789").await;
+        assert_eq!(read_all(&cache, "a", 0, None).await.unwrap(), b"0123456789");
+        assert_eq!(read_all(&cache, "a", 2, Some(4)).await.unwrap(), b"234");
+        assert_eq!(read_all(&cache, "a", 5, None).await.unwrap(), b"56789");
+
+        // an interrupted write leaves nothing behind
+        let mut writer = cache.writer("b", 10).await.unwrap();
+        writer.write(b"01234").await.unwrap();
+        drop(writer);
+        assert_eq!(read_all(&cache, "b", 0, None).await, None);
+
+        // larger than an eighth of the capacity
+        assert!(cache.writer("c", 11).await.is_none());
+
+        // a new instance picks up the files
+        let cache = BlobCache::new(dir.clone(), 80).unwrap();
+        assert_eq!(cache.size(), 10);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_blob_eviction() {
+        let dir = std::env::temp_dir().join(format!("hulylake-blobs-{}", Uuid::new_v4()));
+
+        let cache = Arc::new(BlobCache::new(dir.clone(), 16).unwrap());
+
+        write(&cache, "a", b"01").await;
+        write(&cache, "b", b"23").await;
+
+        // a is read, so b is the least recently used one
+        assert!(read_all(&cache, "a", 0, None).await.is_some());
+
+        for blob in ["c", "d", "e", "f", "g", "h", "i"] {
+            write(&cache, blob, b"45").await;
+        }
+
+        assert!(read_all(&cache, "a", 0, None).await.is_some());
+        assert_eq!(read_all(&cache, "b", 0, None).await, None);
+        assert_eq!(cache.size(), 16);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,237 @@
+use std::error::Error as StdError;
+
+use async_stream::try_stream;
+use bytes::{Bytes, BytesMut};
+use futures::stream::StreamExt;
+use futures_util::Stream;
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, so that chunk boundaries are stable across builds
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9e3779b97f4a7c15;
+    let mut i = 0;
+
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+
+    table
+}
+
+static GEAR: [u64; 256] = gear_table();
+
+/// Content-defined chunker based on the gear rolling hash with normalized chunking (FastCDC).
+/// Boundaries depend only on the content, so an insertion or deletion only
+/// affects the chunks around the change.
+#[derive(Debug, Clone)]
+pub struct Chunker {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+
+    // stricter mask is used before the average size, looser after
+    mask_small: u64,
+    mask_large: u64,
+
+    hash: u64,
+    length: usize,
+}
+
+impl Chunker {
+    pub fn new(avg_size: usize) -> Self {
+        let avg_size = avg_size.next_power_of_two().max(64);
+        let bits = avg_size.trailing_zeros();
+
+        // use the high bits, they depend on the last 64 bytes of input
+        let mask = |bits: u32| (u64::MAX >> (64 - bits)) << (64 - bits);
+
+        Self {
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: avg_size * 4,
+            mask_small: mask(bits + 1),
+            mask_large: mask(bits - 1),
+            hash: 0,
+            length: 0,
+        }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Feeds `data` into the rolling hash, returns the position right after the chunk boundary
+    /// if there is one. Bytes after the boundary are not consumed.
+    pub fn next_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        for (i, byte) in data.iter().enumerate() {
+            self.hash = (self.hash << 1).wrapping_add(GEAR[*byte as usize]);
+            self.length += 1;
+
+            if self.length < self.min_size {
+                continue;
+            }
+
+            let mask = if self.length < self.avg_size {
+                self.mask_small
+            } else {
+                self.mask_large
+            };
+
+            if self.hash & mask == 0 || self.length >= self.max_size {
+                self.hash = 0;
+                self.length = 0;
+                return Some(i + 1);
+            }
+        }
+
+        None
+    }
+}
+
+/// Splits a byte stream into content-defined chunks.
+pub fn chunks<S, E>(
+    mut source: S,
+    avg_size: usize,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: StdError + Send + Sync + 'static,
+{
+    let mut chunker = Chunker::new(avg_size);
+
+    try_stream! {
+        let mut buffer = BytesMut::with_capacity(chunker.max_size());
+
+        while let Some(bytes) = source.next().await {
+            let mut bytes = bytes.map_err(std::io::Error::other)?;
+
+            while !bytes.is_empty() {
+                match chunker.next_boundary(&bytes) {
+                    Some(boundary) => {
+                        buffer.extend_from_slice(&bytes.split_to(boundary));
+                        yield buffer.split().freeze();
+                    }
+                    None => {
+                        buffer.extend_from_slice(&bytes);
+                        bytes.clear();
+                    }
+                }
+            }
+        }
+
+        if !buffer.is_empty() {
+            yield buffer.freeze();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    fn random_bytes(length: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn split(data: &[u8], avg_size: usize) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::new(avg_size);
+        let mut chunks = Vec::new();
+        let mut rest = data;
+
+        while let Some(boundary) = chunker.next_boundary(rest) {
+            chunks.push(rest[..boundary].to_vec());
+            rest = &rest[boundary..];
+        }
+
+        if !rest.is_empty() {
+            chunks.push(rest.to_vec());
+        }
+
+        chunks
+    }
+
+    #[test]
+    fn test_chunk_sizes() {
+        let data = random_bytes(1024 * 1024, 1);
+        let chunks = split(&data, 4096);
+
+        assert_eq!(chunks.concat(), data);
+
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() >= 1024, "chunk too small: {}", chunk.len());
+            assert!(chunk.len() <= 4096 * 4, "chunk too large: {}", chunk.len());
+        }
+
+        let average = data.len() / chunks.len();
+        assert!((2048..=8192).contains(&average), "average {average}");
+    }
+
+    #[test]
+    fn test_chunk_deterministic() {
+        let data = random_bytes(256 * 1024, 2);
+
+        assert_eq!(split(&data, 4096), split(&data, 4096));
+    }
+
+    #[test]
+    fn test_chunk_insertion() {
+        let data = random_bytes(512 * 1024, 3);
+
+        let mut modified = data.clone();
+        modified.splice(100_000..100_000, [1, 2, 3]);
+
+        let original = split(&data, 4096);
+        let modified = split(&modified, 4096);
+
+        let unchanged = modified.iter().filter(|c| original.contains(c)).count();
+
+        // only chunks around the insertion are affected
+        assert!(
+            unchanged + 3 >= original.len(),
+            "{unchanged} of {}",
+            original.len()
+        );
+    }
+
+    #[test]
+    fn test_chunk_zeroes() {
+        let data = vec![0u8; 100 * 1024];
+        let chunks = split(&data, 4096);
+
+        assert_eq!(chunks.concat(), data);
+        assert!(chunks.iter().all(|c| c.len() <= 4096 * 4));
+    }
+
+    #[tokio::test]
+    async fn test_chunks_stream() {
+        let data = random_bytes(300 * 1024, 4);
+
+        // boundaries must not depend on how the input is split
+        let source = stream::iter(
+            data.chunks(1000)
+                .map(|c| Ok::<_, std::io::Error>(Bytes::copy_from_slice(c)))
+                .collect::<Vec<_>>(),
+        );
+
+        let chunks = chunks(source, 4096)
+            .map(|c| c.unwrap().to_vec())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(chunks, split(&data, 4096));
+    }
+}
@@ -1,18 +1,127 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Utc};
+use lru::LruCache;
+use serde::Deserialize;
 use size::Size;
-use tokio::sync::{RwLock, mpsc};
+use tokio::sync::{Mutex, RwLock, mpsc, watch};
 use tracing::*;
 use uuid::Uuid;
 
+use crate::cache::BlobCache;
 use crate::config::CONFIG;
 use crate::handlers::{ApiError, PartData};
-use crate::merge;
+use crate::merge::{self, MergeStrategy};
+use crate::metrics;
 use crate::mutex::KeyMutex;
-use crate::postgres::{ObjectPart, Pool};
+use crate::postgres::{DbError, Fence, Pool};
+use crate::recovery::{self, ManifestPart};
 use crate::s3::S3Client;
-use crate::{blob, postgres, recovery};
+use crate::{blob, postgres, retention};
+
+// compaction limits of a workspace are stored at this key
+pub const COMPACTION_KEY: &str = "_compaction";
+
+// limits are read on every write, a change takes effect within the ttl
+const COMPACTION_TTL: Duration = Duration::from_secs(60);
+const COMPACTION_CACHE_ENTRIES: usize = 1024;
+
+/// Compaction limits, an unset limit falls back to the workspace and then to the config,
+/// a limit of 0 is never reached.
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub parts: Option<usize>,
+    pub bytes: Option<u64>,
+    pub age_ms: Option<u64>,
+}
+
+impl Limits {
+    fn from_config() -> Self {
+        Self {
+            parts: Some(CONFIG.compact_parts_limit),
+            bytes: Some(CONFIG.compact_bytes_limit.bytes() as u64),
+            age_ms: Some(CONFIG.compact_age_ms),
+        }
+    }
+
+    fn or(self, other: Self) -> Self {
+        Self {
+            parts: self.parts.or(other.parts),
+            bytes: self.bytes.or(other.bytes),
+            age_ms: self.age_ms.or(other.age_ms),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Compaction {
+    #[serde(flatten)]
+    pub limits: Limits,
+
+    #[serde(default)]
+    pub strategies: HashMap<MergeStrategy, Limits>,
+}
+
+impl Compaction {
+    fn limits(&self, merge_strategy: MergeStrategy) -> Limits {
+        self.strategies
+            .get(&merge_strategy)
+            .copied()
+            .unwrap_or_default()
+            .or(self.limits)
+            .or(Limits::from_config())
+    }
+}
+
+// the limit which the object reached, parts after the first one were appended since the last compaction,
+// large parts are stored as they are and do not count towards the bytes limit
+fn trigger(limits: &Limits, parts: &[&PartData], now: DateTime<Utc>) -> Option<&'static str> {
+    let appended = parts.get(1..).unwrap_or_default();
+    if appended.is_empty() {
+        return None;
+    }
+
+    let reached =
+        |limit: Option<u64>, value: u64| limit.is_some_and(|limit| limit > 0 && value >= limit);
+
+    if reached(limits.parts.map(|limit| limit as u64), parts.len() as u64) {
+        return Some("parts");
+    }
+
+    let bytes = appended
+        .iter()
+        .map(|part| part.size as u64)
+        .filter(|size| *size < CONFIG.multipart_threshold.bytes() as u64)
+        .sum();
+    if reached(limits.bytes, bytes) {
+        return Some("bytes");
+    }
+
+    let oldest = appended.iter().map(|part| part.date).min().unwrap();
+    let age = (now - oldest).num_milliseconds().max(0) as u64;
+    if reached(limits.age_ms, age) {
+        return Some("age");
+    }
+
+    None
+}
+
+async fn find(s3: &Arc<S3Client>, pool: &Pool, workspace: Uuid) -> anyhow::Result<Compaction> {
+    let parts = postgres::find_parts::<PartData>(pool, workspace, COMPACTION_KEY).await?;
+    if parts.is_empty() {
+        return Ok(Compaction::default());
+    }
+
+    let document = merge::project(s3.clone(), None, None, parts, "")
+        .await
+        .map_err(|error| anyhow::anyhow!("{error}"))?
+        .unwrap_or_default();
+
+    Ok(serde_json::from_value(document)?)
+}
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct CompactTask {
@@ -22,97 +131,272 @@ pub struct CompactTask {
 
 pub struct CompactWorker {
     ingest_tx: mpsc::Sender<CompactTask>,
-    ingest_handle: tokio::task::JoinHandle<()>,
-    compact_handle: tokio::task::JoinHandle<()>,
+    ingest_handle: Mutex<Option<tokio::task::JoinHandle<()>>>,
+    compact_handle: Mutex<Option<tokio::task::JoinHandle<()>>>,
+
+    s3: Arc<S3Client>,
+    pool: Pool,
+    shutdown_tx: watch::Sender<bool>,
+    pending_tasks: Arc<RwLock<HashSet<CompactTask>>>,
+    current_task: Arc<Mutex<Option<CompactTask>>>,
+    compactions: std::sync::Mutex<LruCache<Uuid, (Instant, Compaction)>>,
 }
 
 impl CompactWorker {
-    pub fn new(s3: Arc<S3Client>, pool: Pool, lock: KeyMutex, buffer_size: usize) -> Self {
+    pub fn new(
+        s3: Arc<S3Client>,
+        blob_cache: Option<Arc<BlobCache>>,
+        pool: Pool,
+        lock: KeyMutex,
+        buffer_size: usize,
+    ) -> Self {
         let (ingest_tx, ingest_rx) = mpsc::channel(buffer_size);
         let (compact_tx, compact_rx) = mpsc::channel(buffer_size);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let shutdown_rx_ingest = shutdown_tx.subscribe();
 
         let pending_tasks = Arc::new(RwLock::new(HashSet::new()));
         let pending_tasks_ingest = pending_tasks.clone();
         let pending_tasks_compact = pending_tasks.clone();
 
+        let current_task = Arc::new(Mutex::new(None));
+        let current_task_compact = current_task.clone();
+
         let ingest_handle = tokio::spawn(async move {
             debug!(buffer_size, "started ingest worker");
-            Self::run_ingest_worker(ingest_rx, compact_tx, pending_tasks_ingest).await
+            Self::run_ingest_worker(
+                ingest_rx,
+                compact_tx,
+                shutdown_rx_ingest,
+                pending_tasks_ingest,
+            )
+            .await
         });
 
+        let compact_s3 = s3.clone();
+        let compact_pool = pool.clone();
         let compact_handle = tokio::spawn(async move {
             debug!(buffer_size, "started compact worker");
             Self::run_compact_worker(
                 compact_rx,
-                s3.clone(),
-                pool,
+                shutdown_rx,
+                compact_s3,
+                blob_cache,
+                compact_pool,
                 lock.clone(),
                 pending_tasks_compact,
+                current_task_compact,
             )
             .await;
         });
 
         Self {
             ingest_tx,
-            ingest_handle,
-            compact_handle,
+            ingest_handle: Mutex::new(Some(ingest_handle)),
+            compact_handle: Mutex::new(Some(compact_handle)),
+            s3,
+            pool,
+            shutdown_tx,
+            pending_tasks,
+            current_task,
+            compactions: std::sync::Mutex::new(LruCache::new(
+                NonZeroUsize::new(COMPACTION_CACHE_ENTRIES).unwrap(),
+            )),
         }
     }
 
     async fn run_ingest_worker(
         mut ingest_rx: mpsc::Receiver<CompactTask>,
         compact_tx: mpsc::Sender<CompactTask>,
+        mut shutdown_rx: watch::Receiver<bool>,
         pending_tasks: Arc<RwLock<HashSet<CompactTask>>>,
     ) {
         loop {
-            while let Some(task) = ingest_rx.recv().await {
-                let is_new = pending_tasks.write().await.insert(task.clone());
-                if !is_new {
-                    continue;
-                }
+            let task = tokio::select! {
+                biased;
 
-                if let Err(err) = compact_tx.send(task.clone()).await {
-                    error!(%err, "failed to send compact task");
-                    pending_tasks.write().await.remove(&task);
-                }
+                _ = shutdown_rx.wait_for(|shutdown| *shutdown) => break,
+
+                task = ingest_rx.recv() => match task {
+                    Some(task) => task,
+                    None => break,
+                },
+            };
+
+            let is_new = pending_tasks.write().await.insert(task.clone());
+            if !is_new {
+                continue;
+            }
+
+            metrics::COMPACT_QUEUE.inc();
+
+            // the task is pending already, so it is saved if the queue is still full on shutdown
+            let sent = tokio::select! {
+                biased;
+
+                _ = shutdown_rx.wait_for(|shutdown| *shutdown) => break,
+
+                sent = compact_tx.send(task.clone()) => sent,
+            };
+
+            if let Err(err) = sent {
+                error!(%err, "failed to send compact task");
+                pending_tasks.write().await.remove(&task);
+                metrics::COMPACT_QUEUE.dec();
             }
         }
+
+        // tasks not ingested yet are saved with the pending ones
+        ingest_rx.close();
+        while let Some(task) = ingest_rx.recv().await {
+            if pending_tasks.write().await.insert(task) {
+                metrics::COMPACT_QUEUE.inc();
+            }
+        }
+
+        debug!("ingest worker stopped");
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn run_compact_worker(
         mut rx: mpsc::Receiver<CompactTask>,
+        mut shutdown_rx: watch::Receiver<bool>,
         s3: Arc<S3Client>,
+        blob_cache: Option<Arc<BlobCache>>,
         pool: Pool,
         lock: KeyMutex,
         pending_tasks: Arc<RwLock<HashSet<CompactTask>>>,
+        current_task: Arc<Mutex<Option<CompactTask>>>,
     ) {
         loop {
-            while let Some(task) = rx.recv().await {
-                let CompactTask { workspace, key } = task.clone();
+            let task = tokio::select! {
+                biased;
 
-                let _guard = lock.lock(workspace, key).await;
+                _ = shutdown_rx.wait_for(|shutdown| *shutdown) => break,
 
-                pending_tasks.write().await.remove(&task);
+                task = rx.recv() => match task {
+                    Some(task) => task,
+                    None => break,
+                },
+            };
+
+            let CompactTask { workspace, key } = task.clone();
+
+            let guard = match lock.lock(workspace, key).await {
+                Ok(guard) => guard,
+                Err(error) => {
+                    error!(%error, "failed to lock key for compaction");
+                    pending_tasks.write().await.remove(&task);
+                    metrics::COMPACT_QUEUE.dec();
+                    metrics::COMPACT_FAILURES.inc();
+                    continue;
+                }
+            };
 
-                let res = compact(s3.clone(), pool.clone(), task.clone()).await;
-                match res {
-                    Ok(_) => debug!(workspace = %task.workspace, key = %task.key, "blob compacted"),
-                    Err(err) => error!(%err, "failed to compact"),
+            // the task stays here until compacted, so that it can be saved if interrupted
+            current_task.lock().await.replace(task.clone());
+            pending_tasks.write().await.remove(&task);
+            metrics::COMPACT_QUEUE.dec();
+
+            let timer = metrics::COMPACT_DURATION.start_timer();
+            match compact(
+                s3.clone(),
+                blob_cache.clone(),
+                pool.clone(),
+                task.clone(),
+                guard.fence(),
+            )
+            .await
+            {
+                Ok(_) => debug!(workspace = %task.workspace, key = %task.key, "blob compacted"),
+                Err(err) => {
+                    metrics::COMPACT_FAILURES.inc();
+                    error!(%err, "failed to compact")
                 }
             }
+            timer.observe_duration();
+
+            guard.release().await;
+            current_task.lock().await.take();
         }
+
+        debug!("compact worker stopped");
     }
 
-    pub async fn try_send(&self, parts: &Vec<ObjectPart<PartData>>) -> bool {
-        if parts.len() >= CONFIG.compact_parts_limit {
-            let task = CompactTask {
-                workspace: parts[0].data.workspace,
-                key: parts[0].data.key.clone(),
-            };
-            self.send(task).await
-        } else {
-            false
+    /// Schedules tasks saved by a previous instance on shutdown.
+    pub async fn restore(&self) -> Result<(), DbError> {
+        let tasks = postgres::take_compact_tasks(&self.pool).await?;
+
+        if !tasks.is_empty() {
+            info!(count = tasks.len(), "restore compact tasks");
+        }
+
+        for (workspace, key) in tasks {
+            self.send(CompactTask { workspace, key }).await;
+        }
+
+        Ok(())
+    }
+
+    /// Schedules compaction if the object reached one of the limits of its workspace.
+    pub async fn try_send(&self, parts: &[&PartData]) -> bool {
+        let Some(first) = parts.first() else {
+            return false;
+        };
+
+        if parts.len() < 2 {
+            return false;
         }
+
+        let compaction = self.compaction(first.workspace).await;
+        let limits = compaction.limits(first.merge_strategy.unwrap_or_default());
+
+        match trigger(&limits, parts, Utc::now()) {
+            Some(reason) => {
+                metrics::COMPACT_TRIGGERED
+                    .with_label_values(&[reason])
+                    .inc();
+
+                let task = CompactTask {
+                    workspace: first.workspace,
+                    key: first.key.clone(),
+                };
+
+                // callers hold the key lock, a full queue drops the task rather than waiting
+                match self.ingest_tx.try_send(task) {
+                    Ok(_) => true,
+                    Err(err) => {
+                        warn!(%err, "failed to schedule compact");
+                        false
+                    }
+                }
+            }
+            None => false,
+        }
+    }
+
+    // compaction limits of the workspace, cached for a while
+    async fn compaction(&self, workspace: Uuid) -> Compaction {
+        if let Some((read, compaction)) = self.compactions.lock().unwrap().get(&workspace)
+            && read.elapsed() < COMPACTION_TTL
+        {
+            return compaction.clone();
+        }
+
+        let compaction = match find(&self.s3, &self.pool, workspace).await {
+            Ok(compaction) => compaction,
+            Err(error) => {
+                warn!(%workspace, %error, "invalid compaction limits");
+                Compaction::default()
+            }
+        };
+
+        self.compactions
+            .lock()
+            .unwrap()
+            .put(workspace, (Instant::now(), compaction.clone()));
+
+        compaction
     }
 
     pub async fn send(&self, task: CompactTask) -> bool {
@@ -126,14 +410,69 @@ impl CompactWorker {
         }
     }
 
-    pub async fn stop(&self) {
-        self.ingest_handle.abort();
-        self.compact_handle.abort();
+    /// Stops accepting tasks, waits for the running compaction up to the deadline
+    /// and saves unprocessed tasks, so that the next instance picks them up.
+    pub async fn stop(&self, deadline: Duration) {
+        let tasks = self.shutdown(deadline).await;
+
+        if tasks.is_empty() {
+            return;
+        }
+
+        let tasks = tasks
+            .into_iter()
+            .map(|task| (task.workspace, task.key))
+            .collect::<Vec<_>>();
+
+        match postgres::save_compact_tasks(&self.pool, &tasks).await {
+            Ok(_) => info!(count = tasks.len(), "saved compact tasks"),
+            Err(error) => error!(%error, "failed to save compact tasks"),
+        }
+    }
+
+    // stops both workers and returns the tasks which were not compacted
+    async fn shutdown(&self, deadline: Duration) -> Vec<CompactTask> {
+        let _ = self.shutdown_tx.send(true);
+
+        if let Some(handle) = self.ingest_handle.lock().await.take() {
+            let _ = handle.await;
+        }
+
+        if let Some(mut handle) = self.compact_handle.lock().await.take()
+            && tokio::time::timeout(deadline, &mut handle).await.is_err()
+        {
+            warn!("compaction did not finish in time, interrupting");
+            handle.abort();
+            let _ = handle.await;
+        }
+
+        let mut tasks = self
+            .pending_tasks
+            .read()
+            .await
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // an interrupted task may still be pending as well
+        if let Some(task) = self.current_task.lock().await.take()
+            && !tasks.contains(&task)
+        {
+            tasks.push(task);
+        }
+
+        tasks
     }
 }
 
 #[instrument(level = "debug", skip_all, fields(workspace, huly_key))]
-async fn compact(s3: Arc<S3Client>, pool: Pool, task: CompactTask) -> anyhow::Result<(), ApiError> {
+async fn compact(
+    s3: Arc<S3Client>,
+    blob_cache: Option<Arc<BlobCache>>,
+    pool: Pool,
+    task: CompactTask,
+    fence: Option<Fence>,
+) -> anyhow::Result<(), ApiError> {
     let pool = pool.clone();
 
     let workspace = task.workspace;
@@ -143,32 +482,38 @@ async fn compact(s3: Arc<S3Client>, pool: Pool, task: CompactTask) -> anyhow::Re
         .record("workspace", workspace.to_string())
         .record("huly_key", &key);
 
-    let parts = postgres::find_parts(&pool, task.workspace, &key).await?;
+    // immutable objects keep the storage they were written with
+    if retention::find(&pool, workspace, &key).await?.immutable {
+        debug!("skip compaction of immutable object");
+        return Ok(());
+    }
+
+    let parts = postgres::find_parts::<PartData>(&pool, task.workspace, &key).await?;
     let first = &parts.first().unwrap().data;
     let last = &parts.last().unwrap().data;
 
-    let stream = merge::stream(s3.clone(), parts.to_vec()).await?;
+    let segments = match first.merge_strategy {
+        Some(MergeStrategy::Concatenate) => Some(merge::segments(&parts)),
+        _ => None,
+    };
+
+    let stream = merge::stream(s3.clone(), blob_cache, None, parts.to_vec()).await?;
 
     let uploaded = blob::upload(
         &s3,
         &pool,
-        Size::from_bytes(stream.content_length),
+        Some(Size::from_bytes(stream.content_length)),
         stream.stream,
     )
     .await?;
 
-    let inline = uploaded.inline.and_then(|inline| {
-        if inline.len() < CONFIG.inline_threshold.bytes() as usize {
-            Some(inline)
-        } else {
-            None
-        }
-    });
+    let inline = uploaded.stored_inline();
 
     let part_data = PartData {
         workspace,
         key: key.to_owned(),
-        part: 0,
+        // keep numbering, so that new parts continue after the compacted ones
+        part: last.part,
         blob: uploaded.s3_key,
         size: uploaded.length,
         etag: last.etag.clone(),
@@ -177,11 +522,156 @@ async fn compact(s3: Arc<S3Client>, pool: Pool, task: CompactTask) -> anyhow::Re
         headers: first.headers.clone(),
         meta: first.meta.clone(),
         merge_strategy: first.merge_strategy,
+        chunks: uploaded.chunks,
+        segments,
+        writer: last.writer.clone(),
     };
-    let obj_parts = vec![&part_data];
+    let obj_parts = vec![ManifestPart::new(&part_data, inline.as_deref())];
 
-    postgres::set_part(&pool, workspace, &key, inline, &part_data).await?;
+    postgres::set_part(
+        &pool,
+        workspace,
+        &key,
+        part_data.part,
+        inline,
+        &part_data,
+        // compaction keeps the expiry of the object
+        None,
+        None,
+        fence.as_ref(),
+    )
+    .await?;
     recovery::set_object(&s3, workspace, &key, obj_parts, None).await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn part_data(size: usize, date: DateTime<Utc>) -> PartData {
+        PartData {
+            workspace: Uuid::nil(),
+            key: "test".to_string(),
+            part: 0,
+            size,
+            blob: Some("test".to_string()),
+            etag: "etag".to_string(),
+            date,
+            headers: None,
+            meta: None,
+            merge_strategy: Some(MergeStrategy::JsonPatch),
+            chunks: None,
+            segments: None,
+            writer: None,
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let compaction = serde_json::from_value::<Compaction>(json!({
+            "parts": 10,
+            "strategies": { "jsonpatch": { "parts": 5, "age_ms": 0 } }
+        }))
+        .unwrap();
+
+        let limits = compaction.limits(MergeStrategy::JsonPatch);
+        assert_eq!(limits.parts, Some(5));
+        assert_eq!(limits.age_ms, Some(0));
+        assert_eq!(
+            limits.bytes,
+            Some(CONFIG.compact_bytes_limit.bytes() as u64)
+        );
+
+        let limits = compaction.limits(MergeStrategy::Concatenate);
+        assert_eq!(limits.parts, Some(10));
+        assert_eq!(limits.age_ms, Some(CONFIG.compact_age_ms));
+    }
+
+    #[test]
+    fn test_trigger() {
+        let now = Utc::now();
+        let limits = Limits {
+            parts: Some(3),
+            bytes: Some(100),
+            age_ms: Some(60_000),
+        };
+
+        let base = part_data(1000, now);
+        let small = part_data(10, now);
+        let large = part_data(100, now);
+        let old = part_data(10, now - chrono::Duration::minutes(2));
+
+        // the base part alone is never compacted
+        assert_eq!(trigger(&limits, &[&base], now), None);
+
+        assert_eq!(trigger(&limits, &[&base, &small], now), None);
+        assert_eq!(
+            trigger(&limits, &[&base, &small, &small], now),
+            Some("parts")
+        );
+        assert_eq!(trigger(&limits, &[&base, &large], now), Some("bytes"));
+
+        // stored apart, compaction would not make it smaller
+        let multipart = part_data(CONFIG.multipart_threshold.bytes() as usize, now);
+        assert_eq!(trigger(&limits, &[&base, &multipart], now), None);
+        assert_eq!(trigger(&limits, &[&base, &old], now), Some("age"));
+
+        let disabled = Limits {
+            parts: Some(0),
+            bytes: Some(0),
+            age_ms: Some(0),
+        };
+        assert_eq!(trigger(&disabled, &[&base, &old, &large], now), None);
+    }
+
+    fn test_worker(lock: KeyMutex, buffer_size: usize) -> CompactWorker {
+        let s3 = S3Client::from_conf(
+            aws_sdk_s3::Config::builder()
+                .behavior_version(aws_sdk_s3::config::BehaviorVersion::latest())
+                .build(),
+        );
+
+        let manager = bb8_postgres::PostgresConnectionManager::new_from_stringlike(
+            "postgresql://root@localhost:1/test",
+            tokio_postgres::NoTls,
+        )
+        .unwrap();
+        let pool = bb8::Pool::builder().build_unchecked(manager);
+
+        CompactWorker::new(Arc::new(s3), None, pool, lock, buffer_size)
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_keeps_tasks() {
+        let lock = KeyMutex::new();
+        let worker = test_worker(lock.clone(), 2);
+
+        let tasks = (0..5)
+            .map(|i| CompactTask {
+                workspace: Uuid::nil(),
+                key: format!("key-{i}"),
+            })
+            .collect::<Vec<_>>();
+
+        // the keys are busy, so that compaction waits and both queues fill up
+        let mut guards = Vec::new();
+        for task in &tasks {
+            guards.push(lock.lock(task.workspace, task.key.clone()).await.unwrap());
+        }
+
+        for task in &tasks {
+            assert!(worker.send(task.clone()).await);
+        }
+
+        let saved = worker.shutdown(Duration::from_millis(50)).await;
+
+        assert_eq!(saved.len(), tasks.len());
+        assert_eq!(
+            saved.into_iter().collect::<HashSet<_>>(),
+            tasks.into_iter().collect::<HashSet<_>>()
+        );
+    }
+}
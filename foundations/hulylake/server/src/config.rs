@@ -38,10 +38,70 @@ pub struct Config {
     // store blobs inline if size is less than this
     pub inline_threshold: Size,
 
+    // blobs smaller than this are not written to s3, the recovery manifest carries their content
+    pub inline_only_threshold: Size,
+
+    // largest json document or patch, stored in s3 if larger than inline_threshold
+    pub json_size_limit: Size,
+
+    // split blobs larger than multipart_threshold into content-defined chunks
+    pub chunking: bool,
+
+    // average size of a content-defined chunk
+    pub chunk_size: Size,
+
     pub cache_control: String,
 
     pub compact_parts_limit: usize,
+
+    // compact once the appended parts hold this many bytes
+    pub compact_bytes_limit: Size,
+
+    // compact once the oldest appended part is this old
+    pub compact_age_ms: u64,
+
     pub compact_buffer_size: usize,
+
+    // memory held by materialized json documents, 0 disables the memory tier,
+    // documents larger than inline_threshold are only kept on disk
+    pub document_cache_size: Size,
+
+    // keep the latest materialized document of each key on local disk
+    pub document_cache_dir: Option<String>,
+
+    // the least recently read documents are evicted from disk beyond this size
+    pub document_cache_disk_size: Size,
+
+    // keep blobs read from s3 on local disk
+    pub blob_cache_dir: Option<String>,
+
+    // the least recently read blobs are evicted beyond this size
+    pub blob_cache_size: Size,
+
+    // how often expired objects and lifecycle rules are swept
+    pub lifecycle_interval_ms: u64,
+
+    // rego policy applied to every request in addition to token claims, needs the rego feature
+    pub policy_file: Option<String>,
+
+    // lock keys across instances with a lease in postgres, needed when running several replicas
+    pub distributed_lock: bool,
+
+    // lease duration of a distributed key lock, renewed while the lock is held
+    pub lock_ttl_ms: u64,
+
+    // how long a request waits for a locked key before failing with 503
+    pub lock_timeout_ms: u64,
+
+    // timeout of each dependency check in readiness probe
+    pub readiness_timeout_ms: u64,
+
+    // keep serving while reporting not ready, so that load balancers can react
+    pub shutdown_delay_ms: u64,
+
+    // how long shutdown may take from the signal, the delay, in-flight requests and
+    // the running compaction included
+    pub shutdown_timeout_ms: u64,
 }
 
 pub mod hulyrs {
@@ -70,11 +130,32 @@ pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
 
         multipart_threshold = "4MB"
         inline_threshold = "100KB"
+        inline_only_threshold = "1KB"
+        json_size_limit = "10MB"
+
+        chunking = false
+        chunk_size = "1MB"
 
         cache_control = "public, no-cache"
 
         compact_parts_limit = 100
+        compact_bytes_limit = "8MB"
+        compact_age_ms = 86400000
         compact_buffer_size = 1000
+
+        document_cache_size = "256MB"
+        document_cache_disk_size = "1GB"
+        blob_cache_size = "10GB"
+
+        lifecycle_interval_ms = 60000
+
+        distributed_lock = false
+        lock_ttl_ms = 30000
+        lock_timeout_ms = 60000
+
+        readiness_timeout_ms = 2000
+        shutdown_delay_ms = 5000
+        shutdown_timeout_ms = 25000
     "#;
 
     let mut builder =
@@ -1,16 +1,17 @@
-use std::{collections::HashMap, fmt::Display, io, str::FromStr, time::SystemTime};
+use std::{collections::HashMap, fmt::Display, io, str::FromStr, sync::Arc, time::SystemTime};
 
 use actix_web::{
-    HttpRequest, HttpResponse,
+    HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder,
     body::SizedStream,
     dev::ServiceRequest,
     http::{
         self, StatusCode,
         header::{self, ContentLength, ContentType, EntityTag, HttpDate, Range},
     },
-    web::{Data, Header, Path, Payload},
+    web::{Data, Header, Path, Payload, Query},
 };
 use aws_sdk_s3::error::SdkError;
+use bytes::Bytes;
 use chrono::{DateTime, Utc};
 use futures::{StreamExt, stream};
 use serde::{Deserialize, Serialize};
@@ -20,17 +21,25 @@ use uuid::Uuid;
 
 use crate::s3::S3Client;
 use crate::{
-    blob,
+    audit, blob,
+    cache::{BlobCache, DocumentCache},
     conditional::{ConditionalMatch, any_match, none_match},
-    merge,
+    lifecycle, merge, meta, metrics, patch,
     postgres::ObjectPart,
+    retention, schema,
 };
 use crate::{compact::CompactWorker, conditional};
 use crate::{
     config::CONFIG,
-    postgres::{self, Pool},
+    postgres::{self, Fence, Pool},
+};
+use crate::{
+    merge::MergeStrategy,
+    recovery::{self, ManifestPart},
 };
-use crate::{merge::MergeStrategy, recovery};
+
+// keys routed to workspace endpoints rather than to objects
+const ENDPOINT_KEYS: [&str; 3] = ["_audit", "_query", "_retention"];
 
 #[derive(Deserialize, Debug)]
 pub struct ObjectPath {
@@ -58,6 +67,15 @@ pub enum ApiError {
     #[error("Precondition Failed")]
     PreconditionFailed,
 
+    #[error(transparent)]
+    Patch(#[from] patch::HulyPatchError),
+
+    #[error(transparent)]
+    Schema(#[from] schema::SchemaError),
+
+    #[error(transparent)]
+    Retention(#[from] retention::RetentionError),
+
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -75,6 +93,41 @@ impl actix_web::error::ResponseError for ApiError {
                 HttpResponse::PreconditionFailed().body("Precondition Failed")
             }
 
+            // a failed test operation means the document has changed
+            ApiError::Patch(error @ patch::HulyPatchError::TestFailed(_)) => {
+                HttpResponse::Conflict().json(serde_json::json!({ "error": error.to_string() }))
+            }
+
+            ApiError::Patch(error) => HttpResponse::UnprocessableEntity()
+                .json(serde_json::json!({ "error": error.to_string() })),
+
+            ApiError::Schema(
+                error @ (schema::SchemaError::InvalidSchema { .. }
+                | schema::SchemaError::MergeStrategy { .. }),
+            ) => HttpResponse::BadRequest().json(serde_json::json!({ "error": error.to_string() })),
+
+            ApiError::Schema(error @ schema::SchemaError::Violation { schema, violations }) => {
+                HttpResponse::UnprocessableEntity().json(serde_json::json!({
+                    "error": error.to_string(),
+                    "schema": schema,
+                    "violations": violations,
+                }))
+            }
+
+            ApiError::Retention(error @ retention::RetentionError::Modify { .. }) => {
+                HttpResponse::Conflict().json(serde_json::json!({ "error": error.to_string() }))
+            }
+
+            ApiError::Retention(error) => {
+                HttpResponse::Forbidden().json(serde_json::json!({ "error": error.to_string() }))
+            }
+
+            // the key is busy or was taken over by another instance, the client may retry
+            ApiError::Db(
+                error @ (postgres::DbError::LockTimeout | postgres::DbError::LeaseLost),
+            ) => HttpResponse::ServiceUnavailable()
+                .json(serde_json::json!({ "error": error.to_string() })),
+
             _ => {
                 tracing::error!(error=?self, "Internal error in http handler");
                 HttpResponse::InternalServerError().body("Internal Server Error")
@@ -109,18 +162,34 @@ fn random_etag() -> String {
 
 #[derive(Debug, Clone)]
 pub struct Headers {
-    pub content_length: Size,
+    pub content_length: Option<Size>,
     pub content_type: Option<String>,
     pub huly_headers: Vec<(String, String)>,
     pub meta: Vec<(String, String)>,
+
+    // the object is deleted after this date
+    pub expires: Option<DateTime<Utc>>,
 }
 
 async fn extract_headers(request: &mut ServiceRequest) -> HandlerResult<(Headers, MergeStrategy)> {
-    let content_length = request
-        .extract::<Header<ContentLength>>()
-        .await
-        .map(|header| Size::from_bytes(*header.0))
-        .map_err(|_| actix_web::error::ErrorBadRequest("invalid content length"))?;
+    // chunked uploads have no content length, the size is known once the body is read
+    let chunked = request
+        .headers()
+        .get(header::TRANSFER_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.to_ascii_lowercase().contains("chunked"));
+
+    let content_length = if chunked && !request.headers().contains_key(header::CONTENT_LENGTH) {
+        None
+    } else {
+        let length = request
+            .extract::<Header<ContentLength>>()
+            .await
+            .map(|header| Size::from_bytes(*header.0))
+            .map_err(|_| actix_web::error::ErrorBadRequest("invalid content length"))?;
+
+        Some(length)
+    };
 
     let content_type = request
         .extract::<Header<ContentType>>()
@@ -169,17 +238,80 @@ async fn extract_headers(request: &mut ServiceRequest) -> HandlerResult<(Headers
         serde_json::to_string(&merge_strategy).unwrap(),
     ));
 
+    let expires = request
+        .headers()
+        .get("Huly-Expires")
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(lifecycle::parse_expires)
+                .ok_or_else(|| actix_web::error::ErrorBadRequest("invalid Huly-Expires header"))
+        })
+        .transpose()?;
+
     Ok((
         Headers {
             content_length,
             content_type,
             huly_headers,
             meta,
+            expires,
         },
         merge_strategy,
     ))
 }
 
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct GetQuery {
+    // return only parts appended after this one
+    pub after_part: Option<u32>,
+
+    // return only the value at this json pointer
+    pub pointer: Option<String>,
+
+    // return object metadata instead of content
+    pub meta: Option<String>,
+
+    // return only this part of a concatenated object
+    pub part: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct PatchQuery {
+    // update object metadata instead of appending a part
+    pub meta: Option<String>,
+
+    // drop the parts after the given part number or etag
+    pub truncate: Option<String>,
+}
+
+// byte offset to read a concatenated object from, if a tail read is requested
+fn extract_tail_offset(
+    request: &ServiceRequest,
+    query: &GetQuery,
+    parts: &[ObjectPart<PartData>],
+) -> HandlerResult<Option<u64>> {
+    if let Some(after_part) = query.after_part {
+        return Ok(Some(merge::tail_offset(parts, after_part)));
+    }
+
+    request
+        .headers()
+        .get("Huly-Since")
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .ok_or_else(|| {
+                    actix_web::error::ErrorBadRequest("invalid Huly-Since header").into()
+                })
+        })
+        .transpose()
+}
+
 async fn extract_range_header(request: &mut ServiceRequest) -> Option<String> {
     request
         .extract::<Header<Range>>()
@@ -193,7 +325,11 @@ pub struct PartData {
     pub key: String,
     pub part: u32,
     pub size: usize,
-    pub blob: String,
+
+    // s3 object of the part, none if the part is chunked or kept only in the database
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
+
     pub etag: String,
 
     #[serde(default)]
@@ -207,6 +343,18 @@ pub struct PartData {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub merge_strategy: Option<MergeStrategy>,
+
+    // content-defined chunks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<blob::Chunk>>,
+
+    // parts a compacted part was made of, so that tail reads work after compaction
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub segments: Option<Vec<merge::Segment>>,
+
+    // account or service which wrote the part
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub writer: Option<String>,
 }
 
 #[instrument(level = "debug", skip_all, fields(workspace, huly_key))]
@@ -220,19 +368,64 @@ pub async fn put(request: HttpRequest, payload: Payload) -> HandlerResult<HttpRe
 
     merge::validate_put_request(merge_strategy, &headers)?;
 
+    // such objects could never be read
+    if ENDPOINT_KEYS.contains(&path.key.as_str()) {
+        return Err(actix_web::error::ErrorBadRequest("the key is reserved").into());
+    }
+
     span.record("workspace", path.workspace.to_string());
     span.record("huly_key", &path.key);
 
     let pool = request.app_data::<Data<Pool>>().unwrap().to_owned();
     let s3 = request.app_data::<Data<S3Client>>().unwrap().to_owned();
+    let validators = request
+        .app_data::<Data<schema::Validators>>()
+        .unwrap()
+        .to_owned();
 
     let parts = postgres::find_parts::<PartData>(&pool, path.workspace, &path.key).await?;
 
     let conditionals = validate_put_conditionals(request.request(), &parts)?;
 
-    let uploaded = blob::upload(&s3, &pool, headers.content_length, payload).await?;
+    if !parts.is_empty() {
+        retention::find(&pool, path.workspace, &path.key)
+            .await?
+            .check_overwrite(&path.key)?;
+    }
+
+    let writer = audit::writer(request.request());
+
+    let immutable = request
+        .headers()
+        .get("Huly-Immutable")
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"true"));
+
+    // other strategies would bypass the schema
+    schema::check_strategy(&pool, path.workspace, &path.key, merge_strategy).await?;
 
-    merge::validate_put_body(merge_strategy, &uploaded)?;
+    // json is parsed on write, so it is read into memory up to json_size_limit and checked
+    // before anything is stored
+    let uploaded = if schema::is_json(merge_strategy) {
+        let body = blob::buffer(headers.content_length, payload).await?;
+
+        merge::validate_put_body(merge_strategy, Some(&body))?;
+        schema::validate_put(
+            &pool,
+            s3.clone().into_inner(),
+            &validators,
+            path.workspace,
+            &path.key,
+            &body,
+        )
+        .await?;
+
+        blob::upload_buffered(&s3, &pool, body).await?
+    } else {
+        blob::upload(&s3, &pool, headers.content_length, payload).await?
+    };
+
+    let inline = uploaded.stored_inline();
+    metrics::uploaded(&uploaded, inline.is_some());
 
     let part_data = PartData {
         workspace: path.workspace,
@@ -246,25 +439,43 @@ pub async fn put(request: HttpRequest, payload: Payload) -> HandlerResult<HttpRe
         headers: Some(headers.huly_headers.clone().into_iter().collect()),
         meta: Some(headers.meta.into_iter().collect()),
         merge_strategy: Some(merge_strategy),
+        chunks: uploaded.chunks,
+        segments: None,
+        writer: writer.clone(),
     };
 
-    let inline = uploaded.inline.and_then(|inline| {
-        if inline.len() < CONFIG.inline_threshold.bytes() as usize {
-            Some(inline)
-        } else {
-            None
-        }
-    });
-
-    let obj_parts = vec![&part_data];
+    let obj_parts = vec![ManifestPart::new(&part_data, inline.as_deref())];
 
     recovery::set_object(&s3, path.workspace, &part_data.key, obj_parts, conditionals).await?;
 
-    postgres::set_part(&pool, path.workspace, &part_data.key, inline, &part_data).await?;
+    postgres::set_part(
+        &pool,
+        path.workspace,
+        &part_data.key,
+        part_data.part,
+        inline,
+        &part_data,
+        // a new object replaces the expiry of the previous one
+        Some(&postgres::ObjectState {
+            expires: headers.expires,
+            immutable,
+        }),
+        Some(&audit::record(
+            audit::Action::Put,
+            Some(&part_data),
+            writer.as_deref(),
+        )),
+        fence(request.request()).as_ref(),
+    )
+    .await?;
 
     let mut response = HttpResponse::Created();
     response.insert_header((header::ETAG, part_data.etag));
 
+    if let Some(chunks) = &part_data.chunks {
+        response.insert_header(("Huly-Chunks-Count", chunks.len().to_string()));
+    }
+
     if uploaded.deduplicated {
         response.insert_header(("Huly-Deduplicated", "true"));
     } else {
@@ -287,26 +498,96 @@ pub async fn patch(request: HttpRequest, payload: Payload) -> HandlerResult<Http
     let mut request = ServiceRequest::from_request(request);
 
     let path = request.extract::<Path<ObjectPath>>().await?.into_inner();
-    let (headers, _) = extract_headers(&mut request).await?;
+    let query = request.extract::<Query<PatchQuery>>().await?.into_inner();
 
     span.record("workspace", path.workspace.to_string());
     span.record("huly_key", &path.key);
 
+    if query.meta.is_some() {
+        return update_meta(request, path, payload).await;
+    }
+
+    if let Some(target) = query.truncate {
+        return truncate(request, path, &target).await;
+    }
+
+    let (headers, _) = extract_headers(&mut request).await?;
+
     let pool = request.app_data::<Data<Pool>>().unwrap().to_owned();
     let s3 = request.app_data::<Data<S3Client>>().unwrap().to_owned();
+    let validators = request
+        .app_data::<Data<schema::Validators>>()
+        .unwrap()
+        .to_owned();
+    let cache = request
+        .app_data::<Data<DocumentCache>>()
+        .unwrap()
+        .to_owned();
 
     let parts = postgres::find_parts::<PartData>(&pool, path.workspace, &path.key).await?;
 
     let mut response = if !parts.is_empty() {
         let conditionals = validate_patch_conditionals(request.request(), &parts)?;
 
+        retention::find(&pool, path.workspace, &path.key)
+            .await?
+            .check_append(&path.key)?;
+
         let merge_strategy = objectpart_strategy(&parts).unwrap();
 
         merge::validate_patch_request(merge_strategy, &headers)?;
 
-        let uploaded = blob::upload(&s3, &pool, headers.content_length, payload).await?;
+        // a patch which does not apply or breaks the schema must not leave a blob behind
+        let (uploaded, document) = if schema::is_json(merge_strategy) {
+            let body = blob::buffer(headers.content_length, payload).await?;
+
+            merge::validate_patch_body(merge_strategy, Some(&body))?;
+
+            let schema = schema::find(
+                &pool,
+                s3.clone().into_inner(),
+                &validators,
+                path.workspace,
+                &path.key,
+            )
+            .await?;
+
+            // the parts are replayed on every read, so they must stay readable together
+            merge::check_json_size(
+                parts.iter().map(|part| part.data.size).sum::<usize>() + body.len(),
+            )?;
+
+            let document = merge::patched_document(
+                s3.clone().into_inner(),
+                blob_cache(request.request()),
+                Some(&cache),
+                parts.clone(),
+                &body,
+            )
+            .await?;
+
+            if let Some(document) = &document {
+                merge::check_json_size(
+                    serde_json::to_vec(document)
+                        .map_err(anyhow::Error::from)?
+                        .len(),
+                )?;
+                schema::validate(&path.key, schema.as_ref(), document)?;
+            }
+
+            (blob::upload_buffered(&s3, &pool, body).await?, document)
+        } else {
+            // objects put before the schema was registered
+            schema::check_strategy(&pool, path.workspace, &path.key, merge_strategy).await?;
+
+            (
+                blob::upload(&s3, &pool, headers.content_length, payload).await?,
+                None,
+            )
+        };
 
-        merge::validate_patch_body(merge_strategy, &uploaded)?;
+        let inline = uploaded.stored_inline();
+        metrics::uploaded(&uploaded, inline.is_some());
 
         let part = parts
             .iter()
@@ -328,13 +609,20 @@ pub async fn patch(request: HttpRequest, payload: Payload) -> HandlerResult<Http
             headers: None,
             meta: None,
             merge_strategy: None,
+
+            chunks: uploaded.chunks,
+            segments: None,
+            writer: audit::writer(request.request()),
         };
 
         let obj_parts = parts
             .iter()
-            .map(|p| &p.data)
-            .chain(std::iter::once(&part_data))
-            .collect::<Vec<&PartData>>();
+            .map(ManifestPart::from)
+            .chain(std::iter::once(ManifestPart::new(
+                &part_data,
+                inline.as_deref(),
+            )))
+            .collect::<Vec<_>>();
 
         recovery::set_object(&s3, path.workspace, &part_data.key, obj_parts, conditionals).await?;
 
@@ -343,13 +631,42 @@ pub async fn patch(request: HttpRequest, payload: Payload) -> HandlerResult<Http
             path.workspace,
             &part_data.key,
             part_data.part,
-            uploaded.inline,
+            inline,
             &part_data,
+            Some(&audit::record(
+                audit::Action::Patch,
+                Some(&part_data),
+                part_data.writer.as_deref(),
+            )),
+            fence(request.request()).as_ref(),
         )
         .await?;
 
+        // writers pay for compaction, rather than the first reader
+        let compact = request.app_data::<Data<CompactWorker>>().unwrap();
+        compact
+            .try_send(
+                &parts
+                    .iter()
+                    .map(|p| &p.data)
+                    .chain(std::iter::once(&part_data))
+                    .collect::<Vec<_>>(),
+            )
+            .await;
+
+        // the next read starts from this document instead of replaying all parts
+        if let Some(document) = &document {
+            cache
+                .insert(path.workspace, &part_data.key, &part_data.etag, document)
+                .await;
+        }
+
         let mut response = HttpResponse::Created();
 
+        if let Some(chunks) = &part_data.chunks {
+            response.insert_header(("Huly-Chunks-Count", chunks.len().to_string()));
+        }
+
         if uploaded.deduplicated {
             response.insert_header(("Huly-Deduplicated", "true"));
         } else {
@@ -366,6 +683,225 @@ pub async fn patch(request: HttpRequest, payload: Payload) -> HandlerResult<Http
     Ok(response.finish())
 }
 
+// rewrites headers and meta of the first part, the content stays as is
+async fn update_meta(
+    request: ServiceRequest,
+    path: ObjectPath,
+    payload: Payload,
+) -> HandlerResult<HttpResponse> {
+    let pool = request.app_data::<Data<Pool>>().unwrap().to_owned();
+    let s3 = request.app_data::<Data<S3Client>>().unwrap().to_owned();
+
+    let mut parts = postgres::find_parts::<PartData>(&pool, path.workspace, &path.key).await?;
+
+    if parts.is_empty() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    // a metadata update must not overwrite a concurrent one
+    let Some(conditionals) = validate_patch_conditionals(request.request(), &parts)? else {
+        return Ok(HttpResponse::PreconditionRequired().body("If-Match is required"));
+    };
+
+    retention::find(&pool, path.workspace, &path.key)
+        .await?
+        .check_append(&path.key)?;
+
+    let body = payload
+        .to_bytes_limited(META_BODY_LIMIT)
+        .await
+        .map_err(|_| actix_web::error::ErrorPayloadTooLarge("metadata is too large"))?
+        .map_err(actix_web::error::ErrorBadRequest)?;
+
+    let update = serde_json::from_slice::<meta::MetaUpdate>(&body)
+        .map_err(|e| actix_web::error::ErrorBadRequest(e.to_string()))?;
+
+    update.apply(&mut parts[0].data)?;
+
+    // new etag, so that clients and caches see the change
+    let last = parts.len() - 1;
+    parts[last].data.etag = random_etag();
+    parts[last].data.date = chrono::Utc::now();
+
+    let obj_parts = parts.iter().map(ManifestPart::from).collect::<Vec<_>>();
+    recovery::set_object(
+        &s3,
+        path.workspace,
+        &path.key,
+        obj_parts,
+        Some(conditionals),
+    )
+    .await?;
+
+    // the first and the last part, which are the same for a single part object
+    let updated = [&parts[0], &parts[last]][..parts.len().min(2)]
+        .iter()
+        .map(|p| (p.data.part, &p.data))
+        .collect::<Vec<_>>();
+    postgres::update_parts(
+        &pool,
+        path.workspace,
+        &path.key,
+        &updated,
+        Some(&audit::record(
+            audit::Action::Meta,
+            Some(&parts[last].data),
+            audit::writer(request.request()).as_deref(),
+        )),
+        fence(request.request()).as_ref(),
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::ETAG, parts[last].data.etag.clone()))
+        .json(meta::summary(&parts)))
+}
+
+const META_BODY_LIMIT: usize = 64 * 1024;
+
+// index of the part to truncate to, matched by etag first and then by part number
+// segments rather than stored parts, so that parts listed by ?meta resolve after compaction
+fn truncate_target(segments: &[merge::Segment], target: &str) -> Option<usize> {
+    segments
+        .iter()
+        .position(|s| s.etag.as_deref() == Some(target))
+        .or_else(|| {
+            let part = target.parse::<u32>().ok()?;
+            segments.iter().position(|s| s.part == part)
+        })
+}
+
+// rewrites a compacted part as its segments up to the given part
+async fn retain_segments(
+    s3: &Data<S3Client>,
+    blob_cache: Option<Arc<BlobCache>>,
+    pool: &Pool,
+    part: &mut ObjectPart<PartData>,
+    until: u32,
+) -> HandlerResult<()> {
+    let mut segments = merge::segments(std::slice::from_ref(part))
+        .into_iter()
+        .take_while(|segment| segment.part <= until)
+        .collect::<Vec<_>>();
+
+    // the last segment is described by the part itself, which gets a new etag
+    if let Some(last) = segments.last_mut() {
+        last.etag = None;
+        last.date = None;
+        last.writer = None;
+    }
+
+    let length = segments.iter().map(|s| s.size as u64).sum::<u64>();
+    let stream = merge::prefix(
+        s3.clone().into_inner(),
+        blob_cache,
+        vec![part.clone()],
+        length,
+    );
+
+    let uploaded = blob::upload(s3, pool, Some(Size::from_bytes(length)), stream.stream).await?;
+
+    part.inline = uploaded.stored_inline().map(|inline| inline.to_vec());
+    part.data.part = until;
+    part.data.blob = uploaded.s3_key;
+    part.data.size = uploaded.length;
+    part.data.chunks = uploaded.chunks;
+    part.data.segments = Some(segments);
+
+    Ok(())
+}
+
+// reverts the object to an earlier part, the parts after it are removed
+async fn truncate(
+    request: ServiceRequest,
+    path: ObjectPath,
+    target: &str,
+) -> HandlerResult<HttpResponse> {
+    let pool = request.app_data::<Data<Pool>>().unwrap().to_owned();
+    let s3 = request.app_data::<Data<S3Client>>().unwrap().to_owned();
+    let cache = request
+        .app_data::<Data<DocumentCache>>()
+        .unwrap()
+        .to_owned();
+
+    let mut parts = postgres::find_parts::<PartData>(&pool, path.workspace, &path.key).await?;
+
+    if parts.is_empty() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let Some(conditionals) = validate_patch_conditionals(request.request(), &parts)? else {
+        return Ok(HttpResponse::PreconditionRequired().body("If-Match is required"));
+    };
+
+    // removed parts are gone for good, the same as overwriting the object
+    retention::find(&pool, path.workspace, &path.key)
+        .await?
+        .check_overwrite(&path.key)?;
+
+    let segments = merge::segments(&parts);
+    let Some(index) = truncate_target(&segments, target) else {
+        return Err(actix_web::error::ErrorBadRequest(format!("unknown part: {target}")).into());
+    };
+    let until = segments[index].part;
+
+    // the stored part holding the segment, a compacted part holds the segments before it too
+    let index = parts.iter().position(|p| p.data.part >= until).unwrap();
+    parts.truncate(index + 1);
+
+    let last = parts.len() - 1;
+    if parts[last].data.part != until {
+        retain_segments(
+            &s3,
+            blob_cache(request.request()),
+            &pool,
+            &mut parts[last],
+            until,
+        )
+        .await?;
+    }
+
+    // new etag, the metadata of the first part may differ from the original one
+    parts[last].data.etag = random_etag();
+    parts[last].data.date = chrono::Utc::now();
+    parts[last].data.writer = audit::writer(request.request());
+
+    let obj_parts = parts.iter().map(ManifestPart::from).collect::<Vec<_>>();
+    recovery::set_object(
+        &s3,
+        path.workspace,
+        &path.key,
+        obj_parts,
+        Some(conditionals),
+    )
+    .await?;
+
+    postgres::truncate_parts(
+        &pool,
+        path.workspace,
+        &path.key,
+        parts[last].data.part,
+        parts[last].inline.clone().map(Bytes::from),
+        &parts[last].data,
+        Some(&audit::record(
+            audit::Action::Truncate,
+            Some(&parts[last].data),
+            parts[last].data.writer.as_deref(),
+        )),
+        fence(request.request()).as_ref(),
+    )
+    .await?;
+
+    // cached documents may include the removed parts
+    cache.remove(path.workspace, &path.key).await;
+
+    debug!(part = parts[last].data.part, "object truncated");
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::ETAG, parts[last].data.etag.clone()))
+        .json(meta::summary(&parts)))
+}
+
 #[instrument(level = "debug", skip_all, fields(workspace, huly_key))]
 pub async fn get(request: HttpRequest) -> HandlerResult<HttpResponse> {
     let span = Span::current();
@@ -373,11 +909,16 @@ pub async fn get(request: HttpRequest) -> HandlerResult<HttpResponse> {
     let mut request = ServiceRequest::from_request(request);
 
     let path = request.extract::<Path<ObjectPath>>().await?.into_inner();
+    let query = request.extract::<Query<GetQuery>>().await?.into_inner();
 
     span.record("workspace", path.workspace.to_string());
     span.record("huly_key", &path.key);
 
     let pool = request.app_data::<Data<Pool>>().unwrap().to_owned();
+    let cache = request
+        .app_data::<Data<DocumentCache>>()
+        .unwrap()
+        .to_owned();
 
     let parts = postgres::find_parts::<PartData>(&pool, path.workspace, &path.key).await?;
 
@@ -385,7 +926,19 @@ pub async fn get(request: HttpRequest) -> HandlerResult<HttpResponse> {
         let etag = objectpart_etag(&parts).unwrap();
         let date = objectpart_date(&parts).unwrap();
 
+        if query.meta.is_some() {
+            return Ok(HttpResponse::Ok()
+                .insert_header((header::ETAG, etag))
+                .insert_header((header::CACHE_CONTROL, CONFIG.cache_control.clone()))
+                .json(meta::summary(&parts)));
+        }
+
+        if let Some(part) = query.part {
+            return get_part(&request, parts, part).await;
+        }
+
         let range = extract_range_header(&mut request).await;
+        let tail_offset = extract_tail_offset(&request, &query, &parts)?;
 
         match none_match(request.request(), Some(etag.clone()))? {
             Some(false) => HttpResponse::NotModified()
@@ -401,6 +954,7 @@ pub async fn get(request: HttpRequest) -> HandlerResult<HttpResponse> {
                     .unwrap()
                     .to_owned()
                     .into_inner();
+                let blob_cache = blob_cache(request.request());
 
                 let headers = parts[0].data.headers.as_ref();
                 if let Some(headers) = headers {
@@ -417,10 +971,24 @@ pub async fn get(request: HttpRequest) -> HandlerResult<HttpResponse> {
                 response.insert_header((header::ETAG, etag));
                 response.insert_header((header::LAST_MODIFIED, HttpDate::from(date)));
                 response.insert_header((header::CACHE_CONTROL, CONFIG.cache_control.clone()));
-
-                match range {
-                    Some(range) => {
-                        let partial = merge::partial(s3, parts, range).await?;
+                insert_part_headers(&mut response, &parts);
+
+                match (query.pointer, tail_offset, range) {
+                    (Some(pointer), _, _) => {
+                        match merge::project(s3, blob_cache, Some(&cache), parts, &pointer).await? {
+                            Some(value) => {
+                                response.insert_header((header::CONTENT_TYPE, "application/json"));
+                                response.json(value)
+                            }
+                            None => HttpResponse::NotFound().finish(),
+                        }
+                    }
+                    (None, Some(offset), _) => {
+                        let stream = merge::tail(s3, blob_cache, parts, offset).await?;
+                        response.body(SizedStream::new(stream.content_length, stream.stream))
+                    }
+                    (None, None, Some(range)) => {
+                        let partial = merge::partial(s3, blob_cache, parts, range).await?;
 
                         if partial.partial {
                             response.status(StatusCode::PARTIAL_CONTENT);
@@ -432,11 +1000,14 @@ pub async fn get(request: HttpRequest) -> HandlerResult<HttpResponse> {
 
                         response.body(SizedStream::new(partial.content_length, partial.stream))
                     }
-                    None => {
+                    (None, None, None) => {
                         let compact = request.app_data::<Data<CompactWorker>>().unwrap();
-                        compact.try_send(&parts).await;
+                        compact
+                            .try_send(&parts.iter().map(|p| &p.data).collect::<Vec<_>>())
+                            .await;
 
-                        let stream = merge::stream(s3.clone(), parts).await?;
+                        let stream =
+                            merge::stream(s3.clone(), blob_cache, Some(&cache), parts).await?;
                         response.body(SizedStream::new(stream.content_length, stream.stream))
                     }
                 }
@@ -449,6 +1020,61 @@ pub async fn get(request: HttpRequest) -> HandlerResult<HttpResponse> {
     Ok(response)
 }
 
+// single part of a concatenated object, with the etag of the part
+async fn get_part(
+    request: &ServiceRequest,
+    parts: Vec<ObjectPart<PartData>>,
+    part: u32,
+) -> HandlerResult<HttpResponse> {
+    let s3 = request
+        .app_data::<Data<S3Client>>()
+        .unwrap()
+        .to_owned()
+        .into_inner();
+    let blob_cache = blob_cache(request.request());
+
+    let headers = parts[0].data.headers.clone().unwrap_or_default();
+    let last_part = parts.last().unwrap().data.part;
+
+    let Some((segment, stream)) = merge::part(s3, blob_cache, parts, part).await? else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let etag = segment.etag.map(EntityTag::new_strong);
+
+    let not_modified =
+        etag.is_some() && none_match(request.request(), etag.clone())? == Some(false);
+
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+
+    if let Some(etag) = etag {
+        response.insert_header((header::ETAG, etag));
+    }
+    if let Some(date) = segment.date {
+        response.insert_header((
+            header::LAST_MODIFIED,
+            HttpDate::from(SystemTime::from(date)),
+        ));
+    }
+    response.insert_header((header::CACHE_CONTROL, CONFIG.cache_control.clone()));
+    response.insert_header(("Huly-Part", segment.part.to_string()));
+    response.insert_header(("Huly-Last-Part", last_part.to_string()));
+
+    if not_modified {
+        return Ok(response.finish());
+    }
+
+    for (header, value) in headers {
+        response.insert_header((header, value));
+    }
+
+    Ok(response.body(SizedStream::new(stream.content_length, stream.stream)))
+}
+
 #[instrument(level = "debug", skip_all, fields(workspace, huly_key))]
 pub async fn head(request: HttpRequest) -> HandlerResult<HttpResponse> {
     let span = Span::current();
@@ -492,9 +1118,10 @@ pub async fn head(request: HttpRequest) -> HandlerResult<HttpResponse> {
                 response.insert_header((header::ETAG, etag));
                 response.insert_header((header::LAST_MODIFIED, HttpDate::from(date)));
                 response.insert_header((header::CACHE_CONTROL, CONFIG.cache_control.clone()));
+                insert_part_headers(&mut response, &parts);
 
                 // see https://github.com/actix/examples/blob/master/forms/multipart-s3/src/main.rs#L67-L79
-                let content_length = merge::content_length(parts);
+                let content_length = merge::content_length(&parts);
                 match content_length {
                     Some(content_length) => response.body(SizedStream::new(
                         content_length as u64,
@@ -511,8 +1138,80 @@ pub async fn head(request: HttpRequest) -> HandlerResult<HttpResponse> {
     Ok(response)
 }
 
-pub async fn delete(_path: Path<ObjectPath>) -> HandlerResult<HttpResponse> {
-    unimplemented!("delete is not implemented")
+#[instrument(level = "debug", skip_all, fields(workspace, huly_key))]
+pub async fn delete(request: HttpRequest) -> HandlerResult<HttpResponse> {
+    let span = Span::current();
+
+    let mut request = ServiceRequest::from_request(request);
+
+    let path = request.extract::<Path<ObjectPath>>().await?.into_inner();
+
+    span.record("workspace", path.workspace.to_string());
+    span.record("huly_key", &path.key);
+
+    let pool = request.app_data::<Data<Pool>>().unwrap().to_owned();
+    let s3 = request.app_data::<Data<S3Client>>().unwrap().to_owned();
+    let cache = request
+        .app_data::<Data<DocumentCache>>()
+        .unwrap()
+        .to_owned();
+
+    let parts = postgres::find_parts::<PartData>(&pool, path.workspace, &path.key).await?;
+
+    if parts.is_empty() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    validate_patch_conditionals(request.request(), &parts)?;
+
+    retention::find(&pool, path.workspace, &path.key)
+        .await?
+        .check_delete(&path.key)?;
+
+    let writer = audit::writer(request.request());
+    delete_object(
+        &s3.into_inner(),
+        &pool,
+        path.workspace,
+        &path.key,
+        &cache,
+        writer.as_deref(),
+        fence(request.request()).as_ref(),
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Removes the object and its recovery manifest, blobs may be shared and stay in place.
+pub async fn delete_object(
+    s3: &S3Client,
+    pool: &Pool,
+    workspace: Uuid,
+    key: &str,
+    cache: &DocumentCache,
+    writer: Option<&str>,
+    fence: Option<&Fence>,
+) -> HandlerResult<()> {
+    // database first, a leftover manifest can only bring back a deleted object on recovery
+    let audit = audit::record(audit::Action::Delete, None, writer);
+    postgres::delete_object(pool, workspace, key, Some(&audit), fence).await?;
+    recovery::delete_object(s3, workspace, key).await?;
+    cache.remove(workspace, key).await;
+
+    Ok(())
+}
+
+// local copies of s3 blobs, if configured
+fn blob_cache(request: &HttpRequest) -> Option<Arc<BlobCache>> {
+    request
+        .app_data::<Data<BlobCache>>()
+        .map(|cache| cache.clone().into_inner())
+}
+
+// lease of the key set by the mutex middleware, none when keys are locked on this instance only
+fn fence(request: &HttpRequest) -> Option<Fence> {
+    request.extensions().get::<Fence>().cloned()
 }
 
 fn objectpart_etag(parts: &Vec<ObjectPart<PartData>>) -> Option<EntityTag> {
@@ -532,7 +1231,7 @@ fn objectpart_strategy(parts: &Vec<ObjectPart<PartData>>) -> Option<MergeStrateg
 fn objectpart_accept_ranges(parts: &Vec<ObjectPart<PartData>>) -> Option<&str> {
     let strategy = objectpart_strategy(parts)?;
     match strategy {
-        MergeStrategy::JsonPatch => None,
+        MergeStrategy::JsonPatch | MergeStrategy::JsonMerge => None,
         _ => {
             if parts.len() == 1 {
                 Some("bytes")
@@ -543,6 +1242,17 @@ fn objectpart_accept_ranges(parts: &Vec<ObjectPart<PartData>>) -> Option<&str> {
     }
 }
 
+// latest part and total length, the starting point for tail reads
+fn insert_part_headers(response: &mut HttpResponseBuilder, parts: &[ObjectPart<PartData>]) {
+    if let Some(last) = parts.last() {
+        response.insert_header(("Huly-Last-Part", last.data.part.to_string()));
+    }
+
+    if let Some(content_length) = merge::content_length(parts) {
+        response.insert_header(("Huly-Total-Length", content_length.to_string()));
+    }
+}
+
 fn validate_patch_conditionals(
     req: &HttpRequest,
     parts: &Vec<ObjectPart<PartData>>,
@@ -552,8 +1262,7 @@ fn validate_patch_conditionals(
     match any_match(req, etag)? {
         Some(false) => Err(ApiError::PreconditionFailed),
         Some(true) => {
-            let parts_data = parts.iter().map(|p| &p.data).collect::<Vec<&PartData>>();
-            let parts_etag = recovery::object_etag(parts_data)?;
+            let parts_etag = recovery::object_etag(parts.iter().map(ManifestPart::from).collect())?;
 
             Ok(Some(ConditionalMatch::IfMatch(parts_etag)))
         }
@@ -569,8 +1278,7 @@ fn validate_put_conditionals(
 
     match any_match(req, etag.clone())? {
         Some(true) => {
-            let parts_data = parts.iter().map(|p| &p.data).collect::<Vec<&PartData>>();
-            let parts_etag = recovery::object_etag(parts_data)?;
+            let parts_etag = recovery::object_etag(parts.iter().map(ManifestPart::from).collect())?;
 
             Ok(Some(ConditionalMatch::IfMatch(parts_etag)))
         }
@@ -596,16 +1304,55 @@ mod tests {
                 key: "test".to_string(),
                 part: 0,
                 size: 0,
-                blob: "test".to_string(),
+                blob: Some("test".to_string()),
                 etag: etag.to_owned(),
                 date: Utc::now(),
                 headers: None,
                 meta: None,
                 merge_strategy: None,
+                chunks: None,
+                segments: None,
+                writer: None,
             },
         }
     }
 
+    #[test]
+    fn test_truncate_target() {
+        let mut parts = vec![object_part("a"), object_part("b"), object_part("1")];
+        for (i, part) in parts.iter_mut().enumerate() {
+            part.data.part = i as u32;
+        }
+
+        let segments = merge::segments(&parts);
+
+        assert_eq!(truncate_target(&segments, "b"), Some(1));
+        assert_eq!(truncate_target(&segments, "0"), Some(0));
+
+        // an etag wins over a part number
+        assert_eq!(truncate_target(&segments, "1"), Some(2));
+
+        assert_eq!(truncate_target(&segments, "c"), None);
+        assert_eq!(truncate_target(&segments, "3"), None);
+
+        // parts compacted into the last one
+        let segment = |part: u32, etag: Option<&str>| merge::Segment {
+            part,
+            size: 1,
+            etag: etag.map(str::to_owned),
+            date: None,
+            writer: None,
+        };
+        parts[2].data.part = 3;
+        parts[2].data.segments = Some(vec![segment(2, Some("x")), segment(3, None)]);
+        let segments = merge::segments(&parts);
+
+        assert_eq!(truncate_target(&segments, "x"), Some(2));
+        assert_eq!(truncate_target(&segments, "2"), Some(2));
+        assert_eq!(truncate_target(&segments, "1"), Some(3));
+        assert_eq!(truncate_target(&segments, "3"), Some(3));
+    }
+
     #[test]
     fn test_objectpart_etag() {
         let parts = vec![object_part("foo"), object_part("bar")];
@@ -640,7 +1387,7 @@ mod tests {
             .insert_header((header::IF_MATCH, "\"foo\""))
             .to_http_request();
         let parts = vec![object_part("foo")];
-        let etag = recovery::object_etag(vec![&parts[0].data]).unwrap();
+        let etag = recovery::object_etag(vec![(&parts[0]).into()]).unwrap();
 
         let res = validate_patch_conditionals(&req, &parts);
         assert!(res.is_ok());
@@ -688,7 +1435,7 @@ mod tests {
             .insert_header((header::IF_MATCH, "\"foo\""))
             .to_http_request();
         let parts = vec![object_part("foo")];
-        let etag = recovery::object_etag(vec![&parts[0].data]).unwrap();
+        let etag = recovery::object_etag(vec![(&parts[0]).into()]).unwrap();
 
         let res = validate_put_conditionals(&req, &parts);
         assert!(res.is_ok());
@@ -0,0 +1,167 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpResponse, web::Data};
+use serde::Serialize;
+use tracing::*;
+
+use crate::config::CONFIG;
+use crate::metrics;
+use crate::postgres::{self, Pool};
+use crate::s3::S3Client;
+
+/// Readiness of the instance to serve traffic, cleared when shutdown begins.
+pub struct Readiness {
+    shutting_down: AtomicBool,
+}
+
+impl Readiness {
+    pub fn new() -> Self {
+        Self {
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Error,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub status: Status,
+    pub latency_ms: u128,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub shutting_down: bool,
+    pub postgres: Check,
+    pub s3: Check,
+}
+
+async fn check<T, E: std::fmt::Display>(
+    timeout: Duration,
+    probe: impl Future<Output = Result<T, E>>,
+) -> Check {
+    let start = Instant::now();
+
+    let error = match tokio::time::timeout(timeout, probe).await {
+        Ok(Ok(_)) => None,
+        Ok(Err(error)) => Some(error.to_string()),
+        Err(_) => Some(format!("timed out after {}ms", timeout.as_millis())),
+    };
+
+    Check {
+        status: if error.is_none() {
+            Status::Ok
+        } else {
+            Status::Error
+        },
+        latency_ms: start.elapsed().as_millis(),
+        error,
+    }
+}
+
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+pub async fn readyz(
+    readiness: Data<Readiness>,
+    pool: Data<Pool>,
+    s3: Data<S3Client>,
+) -> HttpResponse {
+    let timeout = Duration::from_millis(CONFIG.readiness_timeout_ms);
+
+    let (postgres, s3) = tokio::join!(
+        check(timeout, postgres::ping(&pool)),
+        check(
+            timeout,
+            metrics::s3(
+                "head_bucket",
+                s3.head_bucket().bucket(&CONFIG.s3_bucket).send()
+            )
+        ),
+    );
+
+    let shutting_down = readiness.is_shutting_down();
+    let ready = !shutting_down && postgres.status == Status::Ok && s3.status == Status::Ok;
+
+    if !ready {
+        warn!(shutting_down, ?postgres, ?s3, "not ready");
+    }
+
+    let report = ReadinessReport {
+        ready,
+        shutting_down,
+        postgres,
+        s3,
+    };
+
+    if ready {
+        HttpResponse::Ok().json(report)
+    } else {
+        HttpResponse::ServiceUnavailable().json(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_check_ok() {
+        let res = check(Duration::from_millis(100), async { Ok::<_, String>(()) }).await;
+
+        assert_eq!(res.status, Status::Ok);
+        assert_eq!(res.error, None);
+    }
+
+    #[tokio::test]
+    async fn test_check_error() {
+        let res = check(Duration::from_millis(100), async {
+            Err::<(), _>("connection refused")
+        })
+        .await;
+
+        assert_eq!(res.status, Status::Error);
+        assert_eq!(res.error, Some("connection refused".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_check_timeout() {
+        let res = check(Duration::from_millis(10), async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok::<_, String>(())
+        })
+        .await;
+
+        assert_eq!(res.status, Status::Error);
+        assert_eq!(res.error, Some("timed out after 10ms".to_string()));
+    }
+
+    #[test]
+    fn test_readiness_shutdown() {
+        let readiness = Readiness::new();
+        assert!(!readiness.is_shutting_down());
+
+        readiness.shutdown();
+        assert!(readiness.is_shutting_down());
+    }
+}
@@ -0,0 +1,285 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tokio::sync::{Mutex, watch};
+use tracing::*;
+use uuid::Uuid;
+
+use crate::cache::DocumentCache;
+use crate::compact;
+use crate::handlers::{self, ApiError, PartData};
+use crate::merge;
+use crate::metrics;
+use crate::mutex::KeyMutex;
+use crate::postgres::{self, Pool};
+use crate::retention;
+use crate::s3::S3Client;
+use crate::schema;
+
+// lifecycle rules of a workspace are stored at this key
+pub const LIFECYCLE_KEY: &str = "_lifecycle";
+
+// writer recorded in the audit log for removed objects
+const LIFECYCLE_WRITER: &str = "service:lifecycle";
+
+// objects deleted per query, the rest is picked up by the next sweep
+const SWEEP_BATCH: i64 = 1000;
+
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+pub struct Rule {
+    #[serde(default)]
+    pub prefix: String,
+
+    // delete objects not written for this many days
+    pub days: u32,
+}
+
+#[derive(Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct Lifecycle {
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+/// Parses Huly-Expires, either an http date or rfc 3339.
+pub fn parse_expires(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .or_else(|_| DateTime::parse_from_rfc2822(value))
+        .map(|date| date.with_timezone(&Utc))
+        .ok()
+}
+
+// keys starting with these hold workspace configuration and are never removed by rules
+const RESERVED: [&str; 3] = [
+    LIFECYCLE_KEY,
+    compact::COMPACTION_KEY,
+    schema::SCHEMA_PREFIX,
+];
+
+pub struct Sweeper {
+    handle: Mutex<Option<tokio::task::JoinHandle<()>>>,
+    shutdown_tx: watch::Sender<bool>,
+}
+
+impl Sweeper {
+    pub fn new(
+        s3: Arc<S3Client>,
+        pool: Pool,
+        lock: KeyMutex,
+        cache: Arc<DocumentCache>,
+        interval: Duration,
+    ) -> Self {
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+
+        let handle = tokio::spawn(async move {
+            debug!(?interval, "started lifecycle sweeper");
+
+            loop {
+                tokio::select! {
+                    biased;
+
+                    _ = shutdown_rx.wait_for(|shutdown| *shutdown) => break,
+
+                    _ = tokio::time::sleep(interval) => {}
+                }
+
+                match sweep(&s3, &pool, &lock, &cache).await {
+                    Ok(0) => {}
+                    Ok(deleted) => info!(deleted, "lifecycle sweep"),
+                    Err(error) => error!(%error, "lifecycle sweep failed"),
+                }
+            }
+
+            debug!("lifecycle sweeper stopped");
+        });
+
+        Self {
+            handle: Mutex::new(Some(handle)),
+            shutdown_tx,
+        }
+    }
+
+    /// Waits for the running sweep, so that no object is left half deleted.
+    pub async fn stop(&self) {
+        let _ = self.shutdown_tx.send(true);
+
+        if let Some(handle) = self.handle.lock().await.take() {
+            let _ = handle.await;
+        }
+    }
+}
+
+async fn sweep(
+    s3: &Arc<S3Client>,
+    pool: &Pool,
+    lock: &KeyMutex,
+    cache: &DocumentCache,
+) -> Result<usize, ApiError> {
+    let mut deleted = 0;
+
+    for (workspace, key) in postgres::find_expired_objects(pool, SWEEP_BATCH).await? {
+        let guard = lock.lock(workspace, key.clone()).await?;
+
+        // the object may have been written again meanwhile
+        let expired = postgres::find_expiry(pool, workspace, &key)
+            .await?
+            .is_some_and(|expires| expires <= Utc::now());
+
+        if expired && deletable(pool, workspace, &key).await? {
+            debug!(%workspace, key, "delete expired object");
+            handlers::delete_object(
+                s3,
+                pool,
+                workspace,
+                &key,
+                cache,
+                Some(LIFECYCLE_WRITER),
+                guard.fence().as_ref(),
+            )
+            .await?;
+            metrics::LIFECYCLE_DELETED
+                .with_label_values(&["expired"])
+                .inc();
+            deleted += 1;
+        }
+
+        guard.release().await;
+    }
+
+    for workspace in postgres::find_workspaces_with_key(pool, LIFECYCLE_KEY).await? {
+        let lifecycle = match find(s3, pool, workspace).await {
+            Ok(lifecycle) => lifecycle,
+            Err(error) => {
+                warn!(%workspace, %error, "invalid lifecycle rules");
+                continue;
+            }
+        };
+
+        for rule in lifecycle.rules {
+            let before = Utc::now() - chrono::Duration::days(rule.days as i64);
+
+            // retained keys are left out by the query, so that they do not fill every page
+            let mut after = String::new();
+
+            loop {
+                let keys = postgres::find_stale_objects(
+                    pool,
+                    workspace,
+                    &rule.prefix,
+                    before,
+                    &RESERVED,
+                    &after,
+                    SWEEP_BATCH,
+                )
+                .await?;
+
+                let Some(last) = keys.last() else {
+                    break;
+                };
+                after = last.clone();
+
+                for key in keys {
+                    let guard = lock.lock(workspace, key.clone()).await?;
+
+                    let parts = postgres::find_parts::<PartData>(pool, workspace, &key).await?;
+                    let stale = parts.last().is_some_and(|last| last.data.date < before);
+
+                    if stale && deletable(pool, workspace, &key).await? {
+                        debug!(%workspace, key, prefix = rule.prefix, "delete stale object");
+                        handlers::delete_object(
+                            s3,
+                            pool,
+                            workspace,
+                            &key,
+                            cache,
+                            Some(LIFECYCLE_WRITER),
+                            guard.fence().as_ref(),
+                        )
+                        .await?;
+                        metrics::LIFECYCLE_DELETED
+                            .with_label_values(&["rule"])
+                            .inc();
+                        deleted += 1;
+                    }
+
+                    guard.release().await;
+                }
+            }
+        }
+    }
+
+    Ok(deleted)
+}
+
+// immutable and held objects stay until released
+async fn deletable(pool: &Pool, workspace: Uuid, key: &str) -> Result<bool, ApiError> {
+    let retention = retention::find(pool, workspace, key).await?;
+
+    if retention.check_delete(key).is_err() {
+        debug!(%workspace, key, ?retention, "object is retained");
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+async fn find(s3: &Arc<S3Client>, pool: &Pool, workspace: Uuid) -> anyhow::Result<Lifecycle> {
+    let parts = postgres::find_parts::<PartData>(pool, workspace, LIFECYCLE_KEY).await?;
+    if parts.is_empty() {
+        return Ok(Lifecycle::default());
+    }
+
+    let document = merge::project(s3.clone(), None, None, parts, "")
+        .await
+        .map_err(|error| anyhow::anyhow!("{error}"))?
+        .unwrap_or_default();
+
+    Ok(serde_json::from_value(document)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_expires() {
+        let expected = "2030-01-02T03:04:05Z".parse::<DateTime<Utc>>().unwrap();
+
+        assert_eq!(parse_expires("2030-01-02T03:04:05Z"), Some(expected));
+        assert_eq!(
+            parse_expires("Wed, 02 Jan 2030 03:04:05 GMT"),
+            Some(expected)
+        );
+        assert_eq!(parse_expires("tomorrow"), None);
+    }
+
+    #[test]
+    fn test_rules() {
+        let lifecycle = serde_json::from_value::<Lifecycle>(json!({
+            "rules": [{ "prefix": "exports/", "days": 30 }, { "days": 365 }]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            lifecycle.rules,
+            vec![
+                Rule {
+                    prefix: "exports/".to_string(),
+                    days: 30
+                },
+                Rule {
+                    prefix: "".to_string(),
+                    days: 365
+                },
+            ]
+        );
+
+        let is_reserved = |key: &str| RESERVED.iter().any(|prefix| key.starts_with(prefix));
+        assert!(is_reserved(LIFECYCLE_KEY));
+        assert!(is_reserved("_compaction"));
+        assert!(is_reserved("_schema/exports/"));
+        assert!(!is_reserved("exports/a"));
+    }
+}
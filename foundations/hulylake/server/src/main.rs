@@ -1,4 +1,4 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use actix_cors::Cors;
 use actix_web::{
@@ -8,6 +8,7 @@ use actix_web::{
     middleware::{Next, from_fn},
     web::{self, Data, Path},
 };
+use tokio::time::Instant;
 use tracing::*;
 use tracing_actix_web::TracingLogger;
 use uuid::Uuid;
@@ -15,17 +16,28 @@ use uuid::Uuid;
 use hulyrs::services::jwt::actix::ServiceRequestExt;
 use hulyrs::services::otel;
 
+mod audit;
 mod blob;
+mod cache;
+mod chunk;
 mod compact;
 mod conditional;
 mod config;
 mod handlers;
+mod health;
+mod lifecycle;
 mod merge;
+mod meta;
+mod metrics;
 mod mutex;
 mod patch;
+mod policy;
 mod postgres;
+mod query;
 mod recovery;
+mod retention;
 mod s3;
+mod schema;
 
 use config::CONFIG;
 
@@ -82,6 +94,17 @@ fn initialize_tracing() {
     }
 }
 
+async fn shutdown_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install signal handler");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     initialize_tracing();
@@ -99,8 +122,12 @@ async fn main() -> anyhow::Result<()> {
         "configuration"
     );
 
-    let lock = mutex::KeyMutex::new();
     let postgres = postgres::pool().await?;
+    let lock = if CONFIG.distributed_lock {
+        mutex::KeyMutex::distributed(postgres.clone())
+    } else {
+        mutex::KeyMutex::new()
+    };
     let s3 = s3::client().await;
 
     match s3.head_bucket().bucket(&CONFIG.s3_bucket).send().await {
@@ -130,6 +157,32 @@ async fn main() -> anyhow::Result<()> {
         let workspace = Uuid::parse_str(&request.extract::<Path<String>>().await?);
 
         if claims.is_system() || Ok(claims.workspace.clone()) == workspace.clone().map(Some) {
+            let policies = request.app_data::<Data<policy::Policies>>().unwrap();
+            let key = policy::request_key(&request);
+
+            let input = policy::PolicyInput {
+                method: request.method().as_str(),
+                workspace: workspace.unwrap_or_default(),
+                key: &key,
+                system: claims.is_system(),
+                claims: serde_json::to_value(&claims).unwrap_or_default(),
+            };
+
+            match policies.permit(&input) {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!(
+                        method = input.method,
+                        key, "Forbidden request, denied by policy"
+                    );
+                    return Err(actix_web::error::ErrorForbidden("Forbidden"));
+                }
+                Err(error) => {
+                    error!(%error, "policy evaluation failed");
+                    return Err(actix_web::error::ErrorInternalServerError("policy error"));
+                }
+            }
+
             request.extensions_mut().insert(claims);
             next.call(request).await
         } else {
@@ -153,20 +206,52 @@ async fn main() -> anyhow::Result<()> {
 
         let mutex = request.app_data::<Data<KeyMutex>>().unwrap().to_owned();
 
-        let _guard = mutex.lock(path.workspace, path.key).await;
+        let guard = mutex
+            .lock(path.workspace, path.key)
+            .await
+            .map_err(handlers::ApiError::from)?;
+
+        // writes check the lease, so that they fail once another instance took the key
+        if let Some(fence) = guard.fence() {
+            request.extensions_mut().insert(fence);
+        }
+
+        let response = next.call(request).await;
+        guard.release().await;
 
-        next.call(request).await
+        response
     }
 
+    let blob_cache = cache::BlobCache::from_config()?.map(Data::new);
+
     let compactor = compact::CompactWorker::new(
         Arc::new(s3.clone()),
+        blob_cache.clone().map(Data::into_inner),
         postgres.clone(),
         lock.clone(),
         CONFIG.compact_buffer_size,
     );
+    compactor.restore().await?;
+
+    let document_cache = Data::new(cache::DocumentCache::from_config()?);
+
+    let sweeper = lifecycle::Sweeper::new(
+        Arc::new(s3.clone()),
+        postgres.clone(),
+        lock.clone(),
+        document_cache.clone().into_inner(),
+        Duration::from_millis(CONFIG.lifecycle_interval_ms),
+    );
+
+    let policies = Data::new(policy::Policies::from_config()?);
+    let validators = Data::new(schema::Validators::new());
+
     let compactor_data = Data::new(compactor);
     let compactor_handle = compactor_data.clone();
 
+    let readiness = Data::new(health::Readiness::new());
+    let readiness_data = readiness.clone();
+
     let server = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
@@ -182,11 +267,26 @@ async fn main() -> anyhow::Result<()> {
             .app_data(Data::new(s3.clone()))
             .app_data(Data::new(lock.clone()))
             .app_data(compactor_data.clone())
+            .app_data(document_cache.clone())
+            .configure(|config| {
+                // blobs are read from s3 only without it
+                if let Some(blob_cache) = &blob_cache {
+                    config.app_data(blob_cache.clone());
+                }
+            })
+            .app_data(policies.clone())
+            .app_data(validators.clone())
+            .app_data(readiness_data.clone())
+            .wrap(from_fn(metrics::middleware))
             .wrap(TracingLogger::default())
             .wrap(cors)
             .service(
                 web::scope("/api/{workspace}")
                     .wrap(from_fn(auth))
+                    .route("/_audit", web::get().to(audit::list))
+                    .route("/_query", web::get().to(query::find))
+                    .route("/_retention", web::get().to(retention::list))
+                    .route("/_retention", web::put().to(retention::set))
                     .route(KEY_PATH, web::head().to(handlers::head))
                     .route(KEY_PATH, web::get().to(handlers::get))
                     .route(KEY_PATH, web::put().to(handlers::put).wrap(from_fn(mutex)))
@@ -194,17 +294,61 @@ async fn main() -> anyhow::Result<()> {
                         KEY_PATH,
                         web::patch().to(handlers::patch).wrap(from_fn(mutex)),
                     )
-                    .route(KEY_PATH, web::delete().to(handlers::delete)),
+                    .route(
+                        KEY_PATH,
+                        web::delete().to(handlers::delete).wrap(from_fn(mutex)),
+                    ),
             )
             .route("/status", web::get().to(async || "ok"))
+            .route("/healthz", web::get().to(health::healthz))
+            .route("/readyz", web::get().to(health::readyz))
+            .route("/metrics", web::get().to(metrics::handler))
     })
     .bind(bind_to)?
+    .disable_signals()
+    .shutdown_timeout(CONFIG.shutdown_timeout_ms.div_ceil(1000))
     .run();
 
     info!("http listener on {}", bind_to);
 
+    let shutdown_timeout = Duration::from_millis(CONFIG.shutdown_timeout_ms);
+    let (deadline_tx, deadline_rx) = tokio::sync::oneshot::channel();
+
+    let server_handle = server.handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+
+        // the delay, in-flight requests and compaction share one deadline
+        let deadline = Instant::now() + shutdown_timeout;
+        let _ = deadline_tx.send(deadline);
+
+        info!("shutdown requested");
+        readiness.shutdown();
+
+        tokio::time::sleep(Duration::from_millis(CONFIG.shutdown_delay_ms).min(shutdown_timeout))
+            .await;
+
+        if tokio::time::timeout_at(deadline, server_handle.stop(true))
+            .await
+            .is_err()
+        {
+            warn!("requests did not finish in time, interrupting");
+            server_handle.stop(false).await;
+        }
+    });
+
     server.await?;
-    compactor_handle.stop().await;
+
+    info!("http listener stopped");
+
+    let deadline = deadline_rx
+        .await
+        .unwrap_or_else(|_| Instant::now() + shutdown_timeout);
+
+    sweeper.stop().await;
+    compactor_handle
+        .stop(deadline.saturating_duration_since(Instant::now()))
+        .await;
 
     Ok(())
 }
@@ -1,37 +1,74 @@
-use std::{io::Error as IoError, pin::Pin, sync::Arc};
+use std::{io::Error as IoError, pin::Pin, str::FromStr, sync::Arc};
 
-use actix_web::error::ErrorBadRequest;
+use actix_web::error::{ErrorBadRequest, ErrorPayloadTooLarge, ErrorRangeNotSatisfiable};
+use actix_web::http::header::Range;
 use async_stream::stream;
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::stream::StreamExt;
 use futures_util::Stream;
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, from_slice};
 use tracing::*;
 
+use crate::cache::{BlobCache, DocumentCache};
 use crate::handlers::PartData;
 use crate::handlers::{HandlerResult, Headers};
+use crate::metrics;
 use crate::patch;
 use crate::postgres::ObjectPart;
 use crate::s3::S3Client;
-use crate::{blob::Blob, config::CONFIG};
+use crate::{blob::Chunk, config::CONFIG};
 
 #[derive(
-    Clone, Copy, Debug, Serialize, Deserialize, Default, strum::EnumString, strum::Display,
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    Serialize,
+    Deserialize,
+    Default,
+    strum::EnumString,
+    strum::Display,
 )]
 #[serde(rename_all = "lowercase")]
 #[strum(serialize_all = "lowercase")]
 pub enum MergeStrategy {
     JsonPatch,
 
+    // json merge patch, rfc 7396
+    JsonMerge,
+
     #[default]
     Concatenate,
 }
 
+// an appended part, as seen by tail and part reads
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub part: u32,
+    pub size: usize,
+
+    // missing in segments compacted before parts were readable on their own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<DateTime<Utc>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub writer: Option<String>,
+}
+
 pub fn validate_put_request(merge_strategy: MergeStrategy, headers: &Headers) -> HandlerResult<()> {
     match merge_strategy {
-        MergeStrategy::JsonPatch
+        MergeStrategy::JsonPatch | MergeStrategy::JsonMerge
             if headers.content_type != Some("application/json".to_string())
-                || headers.content_length > CONFIG.inline_threshold =>
+                || headers
+                    .content_length
+                    .is_some_and(|length| length > CONFIG.json_size_limit) =>
         {
             Err(ErrorBadRequest("invalid content type and length").into())
         }
@@ -40,9 +77,9 @@ pub fn validate_put_request(merge_strategy: MergeStrategy, headers: &Headers) ->
     }
 }
 
-pub fn validate_put_body(merge_strategy: MergeStrategy, blob: &Blob) -> HandlerResult<()> {
+pub fn validate_put_body(merge_strategy: MergeStrategy, body: Option<&Bytes>) -> HandlerResult<()> {
     match merge_strategy {
-        MergeStrategy::JsonPatch => match blob.inline.as_ref() {
+        MergeStrategy::JsonPatch | MergeStrategy::JsonMerge => match body {
             Some(inline) => {
                 from_slice::<Value>(inline).map_err(|e| ErrorBadRequest(e.to_string()))?;
                 Ok(())
@@ -61,7 +98,18 @@ pub fn validate_patch_request(
     match merge_strategy {
         MergeStrategy::JsonPatch
             if headers.content_type != Some("application/json-patch+json".to_string())
-                || headers.content_length > CONFIG.inline_threshold =>
+                || headers
+                    .content_length
+                    .is_some_and(|length| length > CONFIG.json_size_limit) =>
+        {
+            Err(ErrorBadRequest("invalid content type and length").into())
+        }
+
+        MergeStrategy::JsonMerge
+            if headers.content_type != Some("application/merge-patch+json".to_string())
+                || headers
+                    .content_length
+                    .is_some_and(|length| length > CONFIG.json_size_limit) =>
         {
             Err(ErrorBadRequest("invalid content type and length").into())
         }
@@ -70,9 +118,12 @@ pub fn validate_patch_request(
     }
 }
 
-pub fn validate_patch_body(merge_strategy: MergeStrategy, blob: &Blob) -> HandlerResult<()> {
+pub fn validate_patch_body(
+    merge_strategy: MergeStrategy,
+    body: Option<&Bytes>,
+) -> HandlerResult<()> {
     match merge_strategy {
-        MergeStrategy::JsonPatch => match blob.inline.as_ref() {
+        MergeStrategy::JsonPatch => match body {
             Some(inline) => {
                 from_slice::<Vec<patch::PatchOperation>>(inline)
                     .map_err(|e| ErrorBadRequest(e.to_string()))?;
@@ -81,10 +132,56 @@ pub fn validate_patch_body(merge_strategy: MergeStrategy, blob: &Blob) -> Handle
             _ => Err(ErrorBadRequest("missing inline body").into()),
         },
 
+        MergeStrategy::JsonMerge => match body {
+            Some(inline) => {
+                from_slice::<Value>(inline).map_err(|e| ErrorBadRequest(e.to_string()))?;
+                Ok(())
+            }
+            _ => Err(ErrorBadRequest("missing inline body").into()),
+        },
+
         _ => Ok(()),
     }
 }
 
+/// Document after applying the patch to the current one, so that patches which cannot apply are
+/// rejected at write time rather than skipped on every read.
+pub async fn patched_document(
+    s3: Arc<S3Client>,
+    blob_cache: Option<Arc<BlobCache>>,
+    cache: Option<&DocumentCache>,
+    parts: Vec<ObjectPart<PartData>>,
+    body: &Bytes,
+) -> HandlerResult<Option<Value>> {
+    let merge_strategy = parts.first().unwrap().data.merge_strategy.unwrap();
+
+    match merge_strategy {
+        MergeStrategy::JsonPatch => {
+            let ops = from_slice::<Vec<patch::PatchOperation>>(body)
+                .map_err(|e| ErrorBadRequest(e.to_string()))?;
+
+            let mut document =
+                json_document(&s3, blob_cache.as_ref(), cache, merge_strategy, parts).await?;
+            patch::apply(&mut document, &ops)?;
+
+            Ok(Some(document))
+        }
+
+        MergeStrategy::JsonMerge => {
+            let merge_patch =
+                from_slice::<Value>(body).map_err(|e| ErrorBadRequest(e.to_string()))?;
+
+            let mut document =
+                json_document(&s3, blob_cache.as_ref(), cache, merge_strategy, parts).await?;
+            json_patch::merge(&mut document, &merge_patch);
+
+            Ok(Some(document))
+        }
+
+        _ => Ok(None),
+    }
+}
+
 pub struct PartialResponse {
     pub partial: bool,
     pub content_range: Option<String>,
@@ -95,18 +192,52 @@ pub struct PartialResponse {
 #[instrument(level = "debug", skip_all)]
 pub async fn partial(
     s3: Arc<S3Client>,
+    blob_cache: Option<Arc<BlobCache>>,
     parts: Vec<ObjectPart<PartData>>,
     range: String,
-) -> anyhow::Result<PartialResponse> {
+) -> HandlerResult<PartialResponse> {
     let part = parts.first().unwrap();
 
-    let mut response = s3
-        .get_object()
-        .bucket(&CONFIG.s3_bucket)
-        .key(&part.data.blob)
-        .range(range)
-        .send()
-        .await?;
+    // inline only blobs have no s3 object to read the range from
+    if let Some(inline) = &part.inline {
+        return partial_inline(Bytes::from(inline.clone()), range);
+    }
+
+    if let Some(chunks) = &part.data.chunks {
+        return partial_chunked(s3, blob_cache, chunks.clone(), part.data.size, range).await;
+    }
+
+    let blob = part
+        .data
+        .blob
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("part {} has no content", part.data.part))?;
+
+    if let Some(cache) = &blob_cache {
+        let size = part.data.size;
+        let (start, end) = satisfiable_range(&range, size)?;
+
+        if let Some(stream) = cache.read(blob, start, Some(end)).await {
+            let content_length = end - start + 1;
+
+            return Ok(PartialResponse {
+                partial: content_length != size as u64,
+                content_range: Some(format!("bytes {start}-{end}/{size}")),
+                content_length,
+                stream: Box::pin(stream),
+            });
+        }
+    }
+
+    let mut response = metrics::s3(
+        "get_object",
+        s3.get_object()
+            .bucket(&CONFIG.s3_bucket)
+            .key(blob)
+            .range(range)
+            .send(),
+    )
+    .await?;
 
     let content_range = response.content_range().map(|s| s.to_string());
     let content_length = response.content_length().map_or(0, |c| c as u64);
@@ -125,6 +256,93 @@ pub async fn partial(
     })
 }
 
+// inclusive byte range of the content, a single range is supported
+fn satisfiable_range(range: &str, size: usize) -> HandlerResult<(u64, u64)> {
+    let range = match Range::from_str(range) {
+        Ok(Range::Bytes(specs)) if specs.len() == 1 => specs[0].to_satisfiable_range(size as u64),
+        _ => None,
+    };
+
+    range.ok_or_else(|| ErrorRangeNotSatisfiable("range not satisfiable").into())
+}
+
+fn partial_inline(inline: Bytes, range: String) -> HandlerResult<PartialResponse> {
+    let size = inline.len();
+    let (start, end) = satisfiable_range(&range, size)?;
+
+    let content_length = end - start + 1;
+    let bytes = inline.slice(start as usize..=end as usize);
+
+    let stream = stream! {
+        yield Ok(bytes);
+    };
+
+    Ok(PartialResponse {
+        partial: content_length != size as u64,
+        content_range: Some(format!("bytes {start}-{end}/{size}")),
+        content_length,
+        stream: Box::pin(stream),
+    })
+}
+
+async fn partial_chunked(
+    s3: Arc<S3Client>,
+    blob_cache: Option<Arc<BlobCache>>,
+    chunks: Vec<Chunk>,
+    size: usize,
+    range: String,
+) -> HandlerResult<PartialResponse> {
+    let (start, end) = satisfiable_range(&range, size)?;
+
+    let slices = chunk_ranges(&chunks, start, end);
+    let content_length = end - start + 1;
+
+    let stream = stream! {
+        for (blob, from, to) in slices {
+            let mut blob_stream = std::pin::pin!(blob_stream(s3.clone(), blob_cache.clone(), blob, from, Some(to)));
+
+            while let Some(bytes) = blob_stream.next().await {
+                let failed = bytes.is_err();
+                yield bytes;
+
+                if failed {
+                    return;
+                }
+            }
+        }
+    };
+
+    Ok(PartialResponse {
+        partial: content_length != size as u64,
+        content_range: Some(format!("bytes {start}-{end}/{size}")),
+        content_length,
+        stream: Box::pin(stream),
+    })
+}
+
+// maps an inclusive byte range of the content to inclusive ranges within chunks
+fn chunk_ranges(chunks: &[Chunk], start: u64, end: u64) -> Vec<(String, u64, u64)> {
+    let mut slices = Vec::new();
+    let mut offset = 0;
+
+    for chunk in chunks {
+        let chunk_start = offset;
+        let chunk_end = offset + chunk.size as u64;
+        offset = chunk_end;
+
+        if chunk_end <= start || chunk_start > end {
+            continue;
+        }
+
+        let from = start.saturating_sub(chunk_start);
+        let to = end.min(chunk_end - 1) - chunk_start;
+
+        slices.push((chunk.blob.clone(), from, to));
+    }
+
+    slices
+}
+
 pub struct StreamResponse {
     pub content_length: u64,
     pub stream: Pin<Box<dyn Stream<Item = Result<Bytes, IoError>> + Send>>,
@@ -133,8 +351,10 @@ pub struct StreamResponse {
 #[instrument(level = "debug", skip_all)]
 pub async fn stream(
     s3: Arc<S3Client>,
+    blob_cache: Option<Arc<BlobCache>>,
+    cache: Option<&DocumentCache>,
     parts: Vec<ObjectPart<PartData>>,
-) -> anyhow::Result<StreamResponse> {
+) -> HandlerResult<StreamResponse> {
     let first = parts.first().unwrap();
     let merge_strategy = first.data.merge_strategy.unwrap();
 
@@ -147,24 +367,15 @@ pub async fn stream(
             }
 
             let stream = stream! {
-                for parts in parts {
-                    match parts.inline {
-                        Some(inline) => {
-                            yield Ok(Bytes::from(inline));
-                        },
-                        None => {
-                            match s3.get_object().bucket(&CONFIG.s3_bucket).key(parts.data.blob).send().await {
-                                Ok(mut response) => {
-                                    while let Some(bytes) = response.body.next().await {
-                                        yield Ok(bytes?);
-                                    }
-                                },
-
-                                Err(error) => {
-                                    yield Err(IoError::new(std::io::ErrorKind::Other, error));
-                                    break;
-                                }
-                            }
+                for part in parts {
+                    let mut part_stream = std::pin::pin!(part_stream(s3.clone(), blob_cache.clone(), part, 0));
+
+                    while let Some(bytes) = part_stream.next().await {
+                        let failed = bytes.is_err();
+                        yield bytes;
+
+                        if failed {
+                            return;
                         }
                     }
                 }
@@ -176,30 +387,10 @@ pub async fn stream(
             })
         }
 
-        MergeStrategy::JsonPatch => {
-            let mut acc = None;
-
-            for part in parts {
-                let part_data = part_data(&s3, part).await?;
-
-                if let Some(acc) = &mut acc {
-                    let ops = serde_json::from_slice::<Vec<patch::PatchOperation>>(&part_data);
-                    match ops {
-                        Ok(ops) => {
-                            if let Err(error) = patch::apply(acc, &ops) {
-                                error!("json patch error: {error}");
-                            }
-                        }
-                        Err(error) => {
-                            error!("json patch deserialization error: {error}");
-                        }
-                    }
-                } else {
-                    acc = Some(serde_json::from_slice::<Value>(&part_data)?);
-                }
-            }
+        MergeStrategy::JsonPatch | MergeStrategy::JsonMerge => {
+            let acc = json_document(&s3, blob_cache.as_ref(), cache, merge_strategy, parts).await?;
 
-            let bytes = serde_json::to_vec(&acc.unwrap())?;
+            let bytes = serde_json::to_vec(&acc).map_err(anyhow::Error::from)?;
             let content_length = bytes.len() as u64;
 
             let stream = stream! {
@@ -214,7 +405,121 @@ pub async fn stream(
     }
 }
 
-pub fn content_length(parts: Vec<ObjectPart<PartData>>) -> Option<usize> {
+// base document with all json patches or merge patches applied, starting from the cached one
+async fn json_document(
+    s3: &Arc<S3Client>,
+    blob_cache: Option<&Arc<BlobCache>>,
+    cache: Option<&DocumentCache>,
+    merge_strategy: MergeStrategy,
+    parts: Vec<ObjectPart<PartData>>,
+) -> HandlerResult<Value> {
+    check_json_size(parts.iter().map(|part| part.data.size).sum())?;
+
+    let (mut acc, skip) = match cache {
+        Some(cache) => match cache.find(&parts).await {
+            Some((document, skip)) => (Some(document), skip),
+            None => (None, 0),
+        },
+        None => (None, 0),
+    };
+
+    let last = parts.last().map(|part| {
+        (
+            part.data.workspace,
+            part.data.key.clone(),
+            part.data.etag.clone(),
+        )
+    });
+    let applied = skip < parts.len();
+
+    for part in parts.into_iter().skip(skip) {
+        let part_data = part_data(s3, blob_cache, part).await?;
+
+        if let Some(acc) = &mut acc {
+            if merge_strategy == MergeStrategy::JsonMerge {
+                match serde_json::from_slice::<Value>(&part_data) {
+                    Ok(merge_patch) => json_patch::merge(acc, &merge_patch),
+                    Err(error) => {
+                        error!("json merge patch deserialization error: {error}");
+                    }
+                }
+                continue;
+            }
+
+            let ops = serde_json::from_slice::<Vec<patch::PatchOperation>>(&part_data);
+            match ops {
+                Ok(ops) => {
+                    if let Err(error) = patch::apply(acc, &ops) {
+                        error!("json patch error: {error}");
+                    }
+                }
+                Err(error) => {
+                    error!("json patch deserialization error: {error}");
+                }
+            }
+        } else {
+            acc = Some(serde_json::from_slice::<Value>(&part_data).map_err(anyhow::Error::from)?);
+        }
+    }
+
+    let acc = acc.ok_or_else(|| anyhow::anyhow!("empty object"))?;
+
+    if let Some(cache) = cache
+        && let Some((workspace, key, etag)) = last
+        && applied
+    {
+        cache.insert(workspace, &key, &etag, &acc).await;
+    }
+
+    Ok(acc)
+}
+
+/// Fails for json documents beyond json_size_limit, which are materialized in memory.
+pub fn check_json_size(size: usize) -> HandlerResult<()> {
+    if size > CONFIG.json_size_limit.bytes() as usize {
+        return Err(ErrorPayloadTooLarge("json document is too large").into());
+    }
+
+    Ok(())
+}
+
+/// Value at the json pointer in the object document, a concatenated object must hold a single json document.
+#[instrument(level = "debug", skip_all)]
+pub async fn project(
+    s3: Arc<S3Client>,
+    blob_cache: Option<Arc<BlobCache>>,
+    cache: Option<&DocumentCache>,
+    parts: Vec<ObjectPart<PartData>>,
+    pointer: &str,
+) -> HandlerResult<Option<Value>> {
+    if !pointer.is_empty() && !pointer.starts_with('/') {
+        return Err(ErrorBadRequest("invalid json pointer").into());
+    }
+
+    let merge_strategy = parts.first().unwrap().data.merge_strategy.unwrap();
+
+    let mut document = match merge_strategy {
+        MergeStrategy::Concatenate => {
+            check_json_size(content_length(&parts).unwrap())?;
+
+            let mut bytes = Vec::new();
+            for part in parts {
+                bytes.extend_from_slice(&part_data(&s3, blob_cache.as_ref(), part).await?);
+            }
+
+            from_slice::<Value>(&bytes)
+                .map_err(|e| ErrorBadRequest(format!("object is not a json document: {e}")))?
+        }
+
+        MergeStrategy::JsonPatch | MergeStrategy::JsonMerge => {
+            json_document(&s3, blob_cache.as_ref(), cache, merge_strategy, parts).await?
+        }
+    };
+
+    Ok(document.pointer_mut(pointer).map(Value::take))
+}
+
+pub fn content_length(parts: &[ObjectPart<PartData>]) -> Option<usize> {
     let first = parts.first().unwrap();
     let merge_strategy = first.data.merge_strategy.unwrap();
 
@@ -229,28 +534,307 @@ pub fn content_length(parts: Vec<ObjectPart<PartData>>) -> Option<usize> {
             Some(content_length)
         }
 
-        MergeStrategy::JsonPatch => None,
+        MergeStrategy::JsonPatch | MergeStrategy::JsonMerge => None,
     }
 }
 
-async fn part_data(s3: &S3Client, part: ObjectPart<PartData>) -> anyhow::Result<Vec<u8>> {
-    match part.inline {
-        Some(inline) => Ok(inline),
+/// Segments of a concatenated object, compacted parts expand into the parts they were made of.
+pub fn segments(parts: &[ObjectPart<PartData>]) -> Vec<Segment> {
+    parts
+        .iter()
+        .flat_map(|part| {
+            let data = &part.data;
+            match &data.segments {
+                Some(segments) => segments
+                    .iter()
+                    .cloned()
+                    .map(|mut segment| {
+                        // the last segment is the part the compacted one was numbered after
+                        if segment.part == data.part && segment.etag.is_none() {
+                            segment.etag = Some(data.etag.clone());
+                            segment.date = Some(data.date);
+                            segment.writer = data.writer.clone();
+                        }
+                        segment
+                    })
+                    .collect(),
+                None => vec![Segment {
+                    part: data.part,
+                    size: data.size,
+                    etag: Some(data.etag.clone()),
+                    date: Some(data.date),
+                    writer: data.writer.clone(),
+                }],
+            }
+        })
+        .collect()
+}
 
-        None => {
-            let response = s3
-                .get_object()
-                .bucket(&CONFIG.s3_bucket)
-                .key(&part.data.blob)
-                .send()
-                .await?;
+/// Byte offset right after the given part.
+pub fn tail_offset(parts: &[ObjectPart<PartData>], after_part: u32) -> u64 {
+    segments(parts)
+        .iter()
+        .take_while(|segment| segment.part <= after_part)
+        .map(|segment| segment.size as u64)
+        .sum()
+}
 
-            let body = response.body.collect().await?;
-            let bytes = body.into_bytes().to_vec();
+/// The given part with its byte offset.
+pub fn find_segment(parts: &[ObjectPart<PartData>], part: u32) -> Option<(u64, Segment)> {
+    let mut offset = 0;
 
-            Ok(bytes)
+    for segment in segments(parts) {
+        if segment.part == part {
+            return Some((offset, segment));
         }
+        offset += segment.size as u64;
     }
+
+    None
+}
+
+fn require_concatenate(parts: &[ObjectPart<PartData>]) -> HandlerResult<()> {
+    let merge_strategy = parts.first().unwrap().data.merge_strategy.unwrap();
+    if merge_strategy != MergeStrategy::Concatenate {
+        return Err(
+            ErrorBadRequest("tail and part reads require concatenate merge strategy").into(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Content of a concatenated object starting at the byte offset.
+#[instrument(level = "debug", skip_all)]
+pub async fn tail(
+    s3: Arc<S3Client>,
+    blob_cache: Option<Arc<BlobCache>>,
+    parts: Vec<ObjectPart<PartData>>,
+    offset: u64,
+) -> HandlerResult<StreamResponse> {
+    require_concatenate(&parts)?;
+
+    let total = content_length(&parts).unwrap_or_default() as u64;
+    if offset > total {
+        return Err(ErrorRangeNotSatisfiable("offset is beyond the end of the object").into());
+    }
+
+    Ok(StreamResponse {
+        content_length: total - offset,
+        stream: Box::pin(slice_stream(s3, blob_cache, parts, offset, total - offset)),
+    })
+}
+
+/// Content of a single part of a concatenated object, None if there is no such part.
+#[instrument(level = "debug", skip_all)]
+pub async fn part(
+    s3: Arc<S3Client>,
+    blob_cache: Option<Arc<BlobCache>>,
+    parts: Vec<ObjectPart<PartData>>,
+    part: u32,
+) -> HandlerResult<Option<(Segment, StreamResponse)>> {
+    require_concatenate(&parts)?;
+
+    let Some((offset, segment)) = find_segment(&parts, part) else {
+        return Ok(None);
+    };
+
+    let length = segment.size as u64;
+    let stream = slice_stream(s3, blob_cache, parts, offset, length);
+
+    Ok(Some((
+        segment,
+        StreamResponse {
+            content_length: length,
+            stream: Box::pin(stream),
+        },
+    )))
+}
+
+/// The first length bytes of a concatenated object.
+pub fn prefix(
+    s3: Arc<S3Client>,
+    blob_cache: Option<Arc<BlobCache>>,
+    parts: Vec<ObjectPart<PartData>>,
+    length: u64,
+) -> StreamResponse {
+    StreamResponse {
+        content_length: length,
+        stream: Box::pin(slice_stream(s3, blob_cache, parts, 0, length)),
+    }
+}
+
+// length bytes of a concatenated object starting at the byte offset
+fn slice_stream(
+    s3: Arc<S3Client>,
+    blob_cache: Option<Arc<BlobCache>>,
+    parts: Vec<ObjectPart<PartData>>,
+    offset: u64,
+    length: u64,
+) -> impl Stream<Item = Result<Bytes, IoError>> + Send {
+    stream! {
+        let mut position = 0;
+        let mut remaining = length;
+
+        for part in parts {
+            if remaining == 0 {
+                return;
+            }
+
+            let start = position;
+            position += part.data.size as u64;
+
+            if position <= offset {
+                continue;
+            }
+
+            let mut part_stream = std::pin::pin!(part_stream(s3.clone(), blob_cache.clone(), part, offset.saturating_sub(start)));
+
+            while let Some(bytes) = part_stream.next().await {
+                let bytes = match bytes {
+                    Ok(bytes) => bytes,
+                    Err(error) => {
+                        yield Err(error);
+                        return;
+                    }
+                };
+
+                let take = remaining.min(bytes.len() as u64);
+                remaining -= take;
+                yield Ok(bytes.slice(..take as usize));
+
+                if remaining == 0 {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// content of a single part starting at skip, inline, from a single s3 object or from its chunks
+fn part_stream(
+    s3: Arc<S3Client>,
+    blob_cache: Option<Arc<BlobCache>>,
+    part: ObjectPart<PartData>,
+    skip: u64,
+) -> impl Stream<Item = Result<Bytes, IoError>> + Send {
+    stream! {
+        if let Some(inline) = part.inline {
+            yield Ok(Bytes::from(inline).slice(skip as usize..));
+            return;
+        }
+
+        let blobs = match part.data.chunks {
+            Some(chunks) => {
+                let size = part.data.size as u64;
+                if skip < size {
+                    chunk_ranges(&chunks, skip, size - 1).into_iter().map(|(blob, from, _)| (blob, from)).collect()
+                } else {
+                    Vec::new()
+                }
+            }
+            None => match part.data.blob {
+                Some(blob) => vec![(blob, skip)],
+                None => {
+                    yield Err(IoError::other(format!("part {} has no content", part.data.part)));
+                    return;
+                }
+            },
+        };
+
+        for (blob, from) in blobs {
+            let mut blob_stream = std::pin::pin!(blob_stream(s3.clone(), blob_cache.clone(), blob, from, None));
+
+            while let Some(bytes) = blob_stream.next().await {
+                let failed = bytes.is_err();
+                yield bytes;
+
+                if failed {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// s3 blob content from the byte at from to the byte at to, inclusive, read from the local
+// cache if there, a whole blob read from s3 is written to the cache
+fn blob_stream(
+    s3: Arc<S3Client>,
+    cache: Option<Arc<BlobCache>>,
+    blob: String,
+    from: u64,
+    to: Option<u64>,
+) -> impl Stream<Item = Result<Bytes, IoError>> + Send {
+    stream! {
+        if let Some(cache) = &cache
+            && let Some(cached) = cache.read(&blob, from, to).await
+        {
+            let mut cached = std::pin::pin!(cached);
+            while let Some(bytes) = cached.next().await {
+                yield bytes;
+            }
+            return;
+        }
+
+        let mut request = s3.get_object().bucket(&CONFIG.s3_bucket).key(&blob);
+        match to {
+            Some(to) => request = request.range(format!("bytes={from}-{to}")),
+            None if from > 0 => request = request.range(format!("bytes={from}-")),
+            None => {}
+        }
+
+        let mut response = match metrics::s3("get_object", request.send()).await {
+            Ok(response) => response,
+            Err(error) => {
+                yield Err(IoError::other(error));
+                return;
+            }
+        };
+
+        let mut writer = match (&cache, from, to, response.content_length()) {
+            (Some(cache), 0, None, Some(size)) => cache.writer(&blob, size as u64).await,
+            _ => None,
+        };
+
+        while let Some(bytes) = response.body.next().await {
+            let bytes = match bytes {
+                Ok(bytes) => bytes,
+                Err(error) => {
+                    yield Err(IoError::other(error));
+                    return;
+                }
+            };
+
+            if let Some(cached) = &mut writer
+                && let Err(error) = cached.write(&bytes).await
+            {
+                warn!(%error, blob, "failed to write cached blob");
+                writer = None;
+            }
+
+            yield Ok(bytes);
+        }
+
+        if let Some(writer) = writer {
+            writer.commit().await;
+        }
+    }
+}
+
+async fn part_data(
+    s3: &Arc<S3Client>,
+    blob_cache: Option<&Arc<BlobCache>>,
+    part: ObjectPart<PartData>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(part.data.size);
+
+    let mut stream = std::pin::pin!(part_stream(s3.clone(), blob_cache.cloned(), part, 0));
+    while let Some(chunk) = stream.next().await {
+        bytes.extend_from_slice(&chunk?);
+    }
+
+    Ok(bytes)
 }
 
 #[cfg(test)]
@@ -277,6 +861,12 @@ mod tests {
                 MergeStrategy::JsonPatch,
                 "application/json",
                 Size::from_mb(1),
+                Ok(()),
+            ),
+            (
+                MergeStrategy::JsonPatch,
+                "application/json",
+                Size::from_mb(100),
                 Err(ErrorBadRequest("invalid content type and length").into()),
             ),
             (
@@ -285,6 +875,18 @@ mod tests {
                 Size::from_kb(10),
                 Err(ErrorBadRequest("invalid content type and length").into()),
             ),
+            (
+                MergeStrategy::JsonMerge,
+                "application/json",
+                Size::from_kb(10),
+                Ok(()),
+            ),
+            (
+                MergeStrategy::JsonMerge,
+                "application/merge-patch+json",
+                Size::from_kb(10),
+                Err(ErrorBadRequest("invalid content type and length").into()),
+            ),
             (
                 MergeStrategy::Concatenate,
                 "text/plain",
@@ -295,10 +897,11 @@ mod tests {
 
         for (merge_strategy, content_type, content_length, expected) in test_cases {
             let headers = Headers {
-                content_length,
+                content_length: Some(content_length),
                 content_type: Some(content_type.to_string()),
                 huly_headers: Vec::new(),
                 meta: Vec::new(),
+                expires: None,
             };
             let res = validate_put_request(merge_strategy, &headers);
             match expected {
@@ -327,15 +930,7 @@ mod tests {
         ];
 
         for (merge_strategy, body, expected) in test_cases {
-            let blob = Blob {
-                hash: "hash".to_string(),
-                s3_key: "key".to_string(),
-                length: body.as_ref().map(|b| b.len()).unwrap_or(0),
-                inline: body,
-                parts_count: None,
-                deduplicated: false,
-            };
-            let res = validate_put_body(merge_strategy, &blob);
+            let res = validate_put_body(merge_strategy, body.as_ref());
             match expected {
                 Ok(_) => assert!(res.is_ok(), "Expected Ok, got Err: {:?}", res.err()),
                 Err(e) => assert_eq!(res.unwrap_err().to_string(), e.to_string()),
@@ -361,7 +956,7 @@ mod tests {
             (
                 MergeStrategy::JsonPatch,
                 "application/json-patch+json",
-                Size::from_mb(1),
+                Size::from_mb(100),
                 Err(ErrorBadRequest("invalid content type and length").into()),
             ),
             (
@@ -370,6 +965,24 @@ mod tests {
                 Size::from_kb(10),
                 Err(ErrorBadRequest("invalid content type and length").into()),
             ),
+            (
+                MergeStrategy::JsonMerge,
+                "application/merge-patch+json",
+                Size::from_kb(10),
+                Ok(()),
+            ),
+            (
+                MergeStrategy::JsonMerge,
+                "application/merge-patch+json",
+                Size::from_mb(100),
+                Err(ErrorBadRequest("invalid content type and length").into()),
+            ),
+            (
+                MergeStrategy::JsonMerge,
+                "application/json-patch+json",
+                Size::from_kb(10),
+                Err(ErrorBadRequest("invalid content type and length").into()),
+            ),
             (
                 MergeStrategy::Concatenate,
                 "text/plain",
@@ -380,10 +993,11 @@ mod tests {
 
         for (merge_strategy, content_type, content_length, expected) in test_cases {
             let headers = Headers {
-                content_length,
+                content_length: Some(content_length),
                 content_type: Some(content_type.to_string()),
                 huly_headers: Vec::new(),
                 meta: Vec::new(),
+                expires: None,
             };
             let res = validate_patch_request(merge_strategy, &headers);
             match expected {
@@ -408,23 +1022,162 @@ mod tests {
                 None,
                 Err(ErrorBadRequest("missing inline body").into()),
             ),
+            (
+                MergeStrategy::JsonMerge,
+                Some(Bytes::from(r#"{ "foo": null, "bar": 1 }"#)),
+                Ok(()),
+            ),
+            (
+                MergeStrategy::JsonMerge,
+                None,
+                Err(ErrorBadRequest("missing inline body").into()),
+            ),
             (MergeStrategy::Concatenate, None, Ok(())),
         ];
 
         for (merge_strategy, body, expected) in test_cases {
-            let blob = Blob {
-                hash: "hash".to_string(),
-                s3_key: "key".to_string(),
-                length: body.as_ref().map(|b| b.len()).unwrap_or(0),
-                inline: body,
-                parts_count: None,
-                deduplicated: false,
-            };
-            let res = validate_patch_body(merge_strategy, &blob);
+            let res = validate_patch_body(merge_strategy, body.as_ref());
             match expected {
                 Ok(_) => assert!(res.is_ok(), "Expected Ok, got Err: {:?}", res.err()),
                 Err(e) => assert_eq!(res.unwrap_err().to_string(), e.to_string()),
             }
         }
     }
+
+    fn object_part(part: u32, size: usize, segments: Option<Vec<Segment>>) -> ObjectPart<PartData> {
+        ObjectPart {
+            inline: None,
+            data: PartData {
+                workspace: uuid::Uuid::nil(),
+                key: "test".to_string(),
+                part,
+                size,
+                blob: Some("test".to_string()),
+                etag: "etag".to_string(),
+                date: chrono::Utc::now(),
+                headers: None,
+                meta: None,
+                merge_strategy: Some(MergeStrategy::Concatenate),
+                chunks: None,
+                segments,
+                writer: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_partial_inline() {
+        let inline = Bytes::from_static(b"0123456789");
+
+        let partial = partial_inline(inline.clone(), "bytes=2-5".to_string()).unwrap();
+        assert!(partial.partial);
+        assert_eq!(partial.content_length, 4);
+        assert_eq!(partial.content_range.as_deref(), Some("bytes 2-5/10"));
+
+        let partial = partial_inline(inline.clone(), "bytes=0-".to_string()).unwrap();
+        assert!(!partial.partial);
+        assert_eq!(partial.content_length, 10);
+
+        assert!(partial_inline(inline, "bytes=20-30".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_tail_offset() {
+        let segment = |part, size| Segment {
+            part,
+            size,
+            etag: None,
+            date: None,
+            writer: None,
+        };
+
+        // part 3 is the compaction of parts 0 to 3
+        let parts = vec![
+            object_part(
+                3,
+                40,
+                Some(vec![
+                    segment(0, 10),
+                    segment(1, 10),
+                    segment(2, 10),
+                    segment(3, 10),
+                ]),
+            ),
+            object_part(4, 5, None),
+            object_part(5, 5, None),
+        ];
+
+        assert_eq!(segments(&parts).len(), 6);
+
+        assert_eq!(tail_offset(&parts, 0), 10);
+        assert_eq!(tail_offset(&parts, 2), 30);
+        assert_eq!(tail_offset(&parts, 3), 40);
+        assert_eq!(tail_offset(&parts, 4), 45);
+        assert_eq!(tail_offset(&parts, 5), 50);
+        assert_eq!(tail_offset(&parts, 100), 50);
+    }
+
+    #[test]
+    fn test_find_segment() {
+        let segment = |part, size| Segment {
+            part,
+            size,
+            etag: Some(format!("etag-{part}")),
+            date: None,
+            writer: None,
+        };
+
+        let mut compacted = vec![segment(0, 10), segment(1, 20)];
+        // compacted before segments had etags
+        compacted.push(Segment {
+            etag: None,
+            ..segment(2, 30)
+        });
+
+        let parts = vec![object_part(2, 60, Some(compacted)), object_part(3, 5, None)];
+
+        let (offset, found) = find_segment(&parts, 1).unwrap();
+        assert_eq!((offset, found.size), (10, 20));
+        assert_eq!(found.etag.as_deref(), Some("etag-1"));
+
+        // the last compacted segment takes the etag of the compacted part
+        let (offset, found) = find_segment(&parts, 2).unwrap();
+        assert_eq!((offset, found.size), (30, 30));
+        assert_eq!(found.etag.as_deref(), Some("etag"));
+
+        let (offset, found) = find_segment(&parts, 3).unwrap();
+        assert_eq!((offset, found.size), (60, 5));
+
+        assert!(find_segment(&parts, 4).is_none());
+    }
+
+    fn chunk(blob: &str, size: usize) -> Chunk {
+        Chunk {
+            blob: blob.to_string(),
+            size,
+        }
+    }
+
+    #[test]
+    fn test_chunk_ranges() {
+        let chunks = vec![chunk("a", 10), chunk("b", 10), chunk("c", 10)];
+
+        let range = |start, end| chunk_ranges(&chunks, start, end);
+
+        assert_eq!(
+            range(0, 29),
+            vec![
+                ("a".to_string(), 0, 9),
+                ("b".to_string(), 0, 9),
+                ("c".to_string(), 0, 9)
+            ]
+        );
+        assert_eq!(range(0, 0), vec![("a".to_string(), 0, 0)]);
+        assert_eq!(
+            range(9, 10),
+            vec![("a".to_string(), 9, 9), ("b".to_string(), 0, 0)]
+        );
+        assert_eq!(range(12, 17), vec![("b".to_string(), 2, 7)]);
+        assert_eq!(range(25, 29), vec![("c".to_string(), 5, 9)]);
+    }
 }
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+
+use actix_web::error::ErrorBadRequest;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::handlers::{HandlerResult, PartData};
+use crate::merge::{self, MergeStrategy};
+use crate::postgres::ObjectPart;
+
+// meta entry holding the merge strategy, set on put and never changed
+const MERGE_STRATEGY_META: &str = "merge-strategy";
+
+#[derive(Serialize, Debug)]
+pub struct PartSummary {
+    pub part: u32,
+    pub size: usize,
+
+    // byte offset of the part in a concatenated object
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<DateTime<Utc>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub writer: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub etag: String,
+    pub merge_strategy: Option<MergeStrategy>,
+    pub size: Option<usize>,
+    pub headers: HashMap<String, String>,
+    pub meta: HashMap<String, String>,
+    pub parts: Vec<PartSummary>,
+}
+
+pub fn summary(parts: &[ObjectPart<PartData>]) -> ObjectMeta {
+    let first = &parts.first().unwrap().data;
+    let last = &parts.last().unwrap().data;
+
+    ObjectMeta {
+        key: first.key.clone(),
+        etag: last.etag.clone(),
+        merge_strategy: first.merge_strategy,
+        size: merge::content_length(parts),
+        headers: first.headers.clone().unwrap_or_default(),
+        meta: first.meta.clone().unwrap_or_default(),
+        parts: match first.merge_strategy {
+            Some(MergeStrategy::Concatenate) => part_list(parts),
+            _ => parts
+                .iter()
+                .map(|p| PartSummary {
+                    part: p.data.part,
+                    size: p.data.size,
+                    offset: None,
+                    etag: Some(p.data.etag.clone()),
+                    date: Some(p.data.date),
+                    writer: p.data.writer.clone(),
+                })
+                .collect(),
+        },
+    }
+}
+
+// parts of a concatenated object as appended, readable one by one with ?part=N
+fn part_list(parts: &[ObjectPart<PartData>]) -> Vec<PartSummary> {
+    let mut offset = 0;
+
+    merge::segments(parts)
+        .into_iter()
+        .map(|segment| {
+            let summary = PartSummary {
+                part: segment.part,
+                size: segment.size,
+                offset: Some(offset),
+                etag: segment.etag,
+                date: segment.date,
+                writer: segment.writer,
+            };
+            offset += segment.size as u64;
+            summary
+        })
+        .collect()
+}
+
+/// Metadata update, a null value removes the entry.
+#[derive(Deserialize, Debug, Default)]
+pub struct MetaUpdate {
+    #[serde(default)]
+    pub headers: HashMap<String, Option<String>>,
+
+    #[serde(default)]
+    pub meta: HashMap<String, Option<String>>,
+}
+
+fn merge_entries(target: &mut HashMap<String, String>, update: HashMap<String, Option<String>>) {
+    for (name, value) in update {
+        let name = name.to_lowercase();
+        match value {
+            Some(value) => target.insert(name, value),
+            None => target.remove(&name),
+        };
+    }
+}
+
+impl MetaUpdate {
+    pub fn apply(self, part: &mut PartData) -> HandlerResult<()> {
+        if self
+            .meta
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case(MERGE_STRATEGY_META))
+        {
+            return Err(ErrorBadRequest("merge strategy cannot be changed").into());
+        }
+
+        let headers = self
+            .headers
+            .iter()
+            .filter_map(|(name, value)| value.as_ref().map(|value| (name, value)));
+        for (name, value) in headers {
+            if actix_web::http::header::HeaderName::from_bytes(name.as_bytes()).is_err()
+                || actix_web::http::header::HeaderValue::from_str(value).is_err()
+            {
+                return Err(ErrorBadRequest(format!("invalid header: {name}")).into());
+            }
+        }
+
+        merge_entries(part.headers.get_or_insert_default(), self.headers);
+        merge_entries(part.meta.get_or_insert_default(), self.meta);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn part_data() -> PartData {
+        PartData {
+            workspace: Uuid::nil(),
+            key: "test".to_string(),
+            part: 0,
+            size: 10,
+            blob: Some("test".to_string()),
+            etag: "etag".to_string(),
+            date: chrono::Utc::now(),
+            headers: Some(HashMap::from([
+                ("content-type".to_string(), "text/plain".to_string()),
+                ("content-disposition".to_string(), "inline".to_string()),
+            ])),
+            meta: Some(HashMap::from([(
+                MERGE_STRATEGY_META.to_string(),
+                "\"concatenate\"".to_string(),
+            )])),
+            merge_strategy: Some(MergeStrategy::Concatenate),
+            chunks: None,
+            segments: None,
+            writer: None,
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut part = part_data();
+
+        let update = serde_json::from_value::<MetaUpdate>(serde_json::json!({
+            "headers": { "Content-Disposition": "attachment; filename=\"a.txt\"", "content-type": null },
+            "meta": { "owner": "alice" }
+        }))
+        .unwrap();
+
+        update.apply(&mut part).unwrap();
+
+        assert_eq!(
+            part.headers,
+            Some(HashMap::from([(
+                "content-disposition".to_string(),
+                "attachment; filename=\"a.txt\"".to_string()
+            )]))
+        );
+        assert_eq!(part.meta.unwrap().get("owner").unwrap(), "alice");
+    }
+
+    #[test]
+    fn test_apply_rejected() {
+        let update = MetaUpdate {
+            meta: HashMap::from([(MERGE_STRATEGY_META.to_string(), None)]),
+            ..Default::default()
+        };
+        assert!(update.apply(&mut part_data()).is_err());
+
+        let update = MetaUpdate {
+            headers: HashMap::from([("bad header".to_string(), Some("x".to_string()))]),
+            ..Default::default()
+        };
+        assert!(update.apply(&mut part_data()).is_err());
+    }
+
+    #[test]
+    fn test_part_list() {
+        let parts = (0..3)
+            .map(|part| ObjectPart {
+                inline: None,
+                data: PartData {
+                    part,
+                    size: 10 * (part as usize + 1),
+                    etag: format!("etag-{part}"),
+                    ..part_data()
+                },
+            })
+            .collect::<Vec<_>>();
+
+        let meta = summary(&parts);
+        assert_eq!(meta.size, Some(60));
+
+        let offsets = meta.parts.iter().map(|p| p.offset).collect::<Vec<_>>();
+        assert_eq!(offsets, vec![Some(0), Some(10), Some(30)]);
+        assert_eq!(meta.parts[2].etag.as_deref(), Some("etag-2"));
+    }
+}
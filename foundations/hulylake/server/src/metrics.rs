@@ -0,0 +1,224 @@
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use actix_web::{
+    Error, HttpResponse,
+    body::{BodySize, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+};
+use prometheus::{
+    Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge,
+};
+
+use crate::blob::Blob;
+
+pub static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "hulylake_http_requests_total",
+        "Number of http requests",
+        &["method", "handler", "status"]
+    )
+    .unwrap()
+});
+
+pub static HTTP_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "hulylake_http_request_duration_seconds",
+        "Http request duration",
+        &["method", "handler", "status"]
+    )
+    .unwrap()
+});
+
+pub static BYTES_IN: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("hulylake_bytes_in_total", "Bytes uploaded by clients").unwrap()
+});
+
+pub static BYTES_OUT: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("hulylake_bytes_out_total", "Bytes sent to clients").unwrap()
+});
+
+pub static BLOBS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "hulylake_blobs_total",
+        "Uploaded blobs, by deduplication result",
+        &["deduplicated"]
+    )
+    .unwrap()
+});
+
+pub static PARTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "hulylake_parts_total",
+        "Written object parts, by storage",
+        &["storage"]
+    )
+    .unwrap()
+});
+
+pub static MULTIPART_PARTS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "hulylake_s3_multipart_parts",
+        "Number of parts in s3 multipart uploads",
+        vec![1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0]
+    )
+    .unwrap()
+});
+
+pub static COMPACT_QUEUE: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "hulylake_compact_queue_depth",
+        "Number of objects waiting for compaction"
+    )
+    .unwrap()
+});
+
+pub static COMPACT_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "hulylake_compact_duration_seconds",
+        "Duration of object compaction"
+    )
+    .unwrap()
+});
+
+pub static COMPACT_TRIGGERED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "hulylake_compact_triggered_total",
+        "Compactions scheduled, by the limit which was reached",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+pub static COMPACT_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "hulylake_compact_failures_total",
+        "Number of failed compactions"
+    )
+    .unwrap()
+});
+
+pub static BLOB_CACHE: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "hulylake_blob_cache_total",
+        "Reads of s3 blobs from the local disk cache, by result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static BLOB_CACHE_SIZE: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "hulylake_blob_cache_bytes",
+        "Size of blobs in the local disk cache"
+    )
+    .unwrap()
+});
+
+pub static DOCUMENT_CACHE: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "hulylake_document_cache_total",
+        "Lookups of materialized json documents, by result",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub static LIFECYCLE_DELETED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "hulylake_lifecycle_deleted_total",
+        "Objects deleted by the lifecycle sweeper, by reason",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+pub static S3_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "hulylake_s3_request_duration_seconds",
+        "Duration of s3 requests",
+        &["operation"]
+    )
+    .unwrap()
+});
+
+pub static DB_POOL_DURATION: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!(
+        "hulylake_db_pool_wait_seconds",
+        "Time spent waiting for a postgres connection from the pool"
+    )
+    .unwrap()
+});
+
+/// Measures duration of an s3 request.
+pub async fn s3<T>(operation: &str, request: impl Future<Output = T>) -> T {
+    let _timer = S3_DURATION.with_label_values(&[operation]).start_timer();
+    request.await
+}
+
+/// Records a blob uploaded by a client and stored as an object part.
+pub fn uploaded(blob: &Blob, inline: bool) {
+    BYTES_IN.inc_by(blob.length as u64);
+
+    BLOBS
+        .with_label_values(&[if blob.deduplicated { "true" } else { "false" }])
+        .inc();
+
+    PARTS
+        .with_label_values(&[if inline { "inline" } else { "s3" }])
+        .inc();
+
+    if let Some(parts_count) = blob.parts_count {
+        MULTIPART_PARTS.observe(parts_count as f64);
+    }
+}
+
+pub async fn middleware(
+    request: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let start = Instant::now();
+
+    let method = request.method().to_string();
+    let handler = request
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_owned());
+
+    let response = next.call(request).await;
+
+    let status = match &response {
+        Ok(response) => response.status(),
+        Err(error) => error.as_response_error().status_code(),
+    };
+
+    let labels = [method.as_str(), handler.as_str(), status.as_str()];
+
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_DURATION
+        .with_label_values(&labels)
+        .observe(start.elapsed().as_secs_f64());
+
+    // head responses declare the length of a body they do not send
+    if let Ok(response) = &response
+        && method != "HEAD"
+        && let BodySize::Sized(size) = response.response().body().size()
+    {
+        BYTES_OUT.inc_by(size);
+    }
+
+    response
+}
+
+pub async fn handler() -> HttpResponse {
+    let mut buffer = Vec::new();
+
+    match TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        Ok(_) => HttpResponse::Ok()
+            .content_type(prometheus::TEXT_FORMAT)
+            .body(buffer),
+        Err(error) => HttpResponse::InternalServerError().body(error.to_string()),
+    }
+}
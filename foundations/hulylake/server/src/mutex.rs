@@ -1,23 +1,293 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use lockable::LockPool;
+use tracing::*;
 use uuid::Uuid;
 
+use crate::config::CONFIG;
+use crate::postgres::{self, DbError, Fence, Pool};
+
 #[derive(Clone)]
 pub struct KeyMutex {
     lock_pool: Arc<LockPool<String>>,
+
+    // set when keys are locked across instances, with a lease in postgres
+    pool: Option<Pool>,
+
+    // how long to wait for a locked key
+    timeout: Duration,
 }
 
 impl KeyMutex {
     pub fn new() -> Self {
         let lock_pool = Arc::new(LockPool::<String>::new());
 
-        KeyMutex { lock_pool }
+        KeyMutex {
+            lock_pool,
+            pool: None,
+            timeout: Duration::from_millis(CONFIG.lock_timeout_ms),
+        }
+    }
+
+    pub fn distributed(pool: Pool) -> Self {
+        KeyMutex {
+            pool: Some(pool),
+            ..Self::new()
+        }
+    }
+
+    /// Locks the key, fails with LockTimeout if it is not free within lock_timeout_ms.
+    pub async fn lock(
+        &self,
+        workspace: Uuid,
+        key: String,
+    ) -> Result<KeyGuard<impl Drop + '_>, DbError> {
+        tokio::time::timeout(self.timeout, self.acquire(workspace, key))
+            .await
+            .map_err(|_| DbError::LockTimeout)?
     }
 
-    pub async fn lock(&self, workspace: Uuid, key: String) -> impl Drop + '_ {
-        self.lock_pool
+    async fn acquire(
+        &self,
+        workspace: Uuid,
+        key: String,
+    ) -> Result<KeyGuard<impl Drop + '_>, DbError> {
+        // requests to the same key on this instance queue up locally first
+        let local = self
+            .lock_pool
             .async_lock(format!("{}:{}", workspace, key))
+            .await;
+
+        let lease = match &self.pool {
+            Some(pool) => Some(Lease::acquire(pool.clone(), workspace, key).await?),
+            None => None,
+        };
+
+        Ok(KeyGuard {
+            _local: local,
+            lease,
+        })
+    }
+}
+
+pub struct KeyGuard<L> {
+    // the lease is released before the local lock
+    lease: Option<Lease>,
+    _local: L,
+}
+
+impl<L> KeyGuard<L> {
+    /// Lease owner to check on writes, none if keys are locked on this instance only.
+    pub fn fence(&self) -> Option<Fence> {
+        self.lease.as_ref().map(|lease| Fence(lease.owner.clone()))
+    }
+
+    /// Releases the lease, then the local lock, so that the next local waiter finds the key free.
+    pub async fn release(mut self) {
+        if let Some(lease) = self.lease.take() {
+            lease.release().await;
+        }
+    }
+}
+
+struct Lease {
+    pool: Pool,
+    workspace: Uuid,
+    key: String,
+    owner: String,
+    renew: tokio::task::JoinHandle<()>,
+    released: bool,
+}
+
+impl Lease {
+    async fn acquire(pool: Pool, workspace: Uuid, key: String) -> Result<Self, DbError> {
+        let owner = ksuid::Ksuid::generate().to_base62();
+        let ttl = Duration::from_millis(CONFIG.lock_ttl_ms);
+
+        let mut backoff = Duration::from_millis(10);
+
+        while !postgres::acquire_lock(&pool, workspace, &key, &owner, ttl).await? {
+            trace!(%workspace, key, "key is locked by another instance");
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(Duration::from_millis(500));
+        }
+
+        // keep the lease while the lock is held, in case the request takes longer than ttl
+        let renew = tokio::spawn({
+            let pool = pool.clone();
+            let key = key.clone();
+            let owner = owner.clone();
+
+            async move {
+                loop {
+                    tokio::time::sleep(ttl / 3).await;
+
+                    match postgres::renew_lock(&pool, workspace, &key, &owner, ttl).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            warn!(%workspace, key, "key lock lease lost");
+                            break;
+                        }
+                        Err(error) => warn!(%workspace, key, %error, "failed to renew key lock"),
+                    }
+                }
+            }
+        });
+
+        Ok(Lease {
+            pool,
+            workspace,
+            key,
+            owner,
+            renew,
+            released: false,
+        })
+    }
+
+    async fn release(mut self) {
+        self.renew.abort();
+        self.released = true;
+
+        if let Err(error) =
+            postgres::release_lock(&self.pool, self.workspace, &self.key, &self.owner).await
+        {
+            // the lease expires by itself
+            warn!(workspace = %self.workspace, key = self.key, %error, "failed to release key lock");
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.renew.abort();
+
+        if self.released {
+            return;
+        }
+
+        let pool = self.pool.clone();
+        let workspace = self.workspace;
+        let key = std::mem::take(&mut self.key);
+        let owner = std::mem::take(&mut self.owner);
+
+        tokio::spawn(async move {
+            if let Err(error) = postgres::release_lock(&pool, workspace, &key, &owner).await {
+                // the lease expires by itself
+                warn!(%workspace, key, %error, "failed to release key lock");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // instances of one deployment share the database, but not the local locks
+    async fn instances(timeout: Duration) -> (KeyMutex, KeyMutex) {
+        let pool = postgres::pool().await.unwrap();
+
+        let instance = || KeyMutex {
+            timeout,
+            ..KeyMutex::distributed(pool.clone())
+        };
+
+        (instance(), instance())
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_contention() {
+        let (first, second) = instances(Duration::from_secs(10)).await;
+        let workspace = Uuid::new_v4();
+
+        let guard = first.lock(workspace, "key".to_string()).await.unwrap();
+
+        let waiter = tokio::spawn(async move {
+            let guard = second.lock(workspace, "key".to_string()).await.unwrap();
+            guard.release().await;
+        });
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!waiter.is_finished());
+
+        guard.release().await;
+        tokio::time::timeout(Duration::from_secs(2), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_timeout() {
+        let (first, second) = instances(Duration::from_millis(200)).await;
+        let workspace = Uuid::new_v4();
+
+        let guard = first.lock(workspace, "key".to_string()).await.unwrap();
+
+        assert!(matches!(
+            second.lock(workspace, "key".to_string()).await,
+            Err(DbError::LockTimeout)
+        ));
+
+        guard.release().await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_fence() {
+        let (first, second) = instances(Duration::from_secs(10)).await;
+        let workspace = Uuid::new_v4();
+        let pool = first.pool.clone().unwrap();
+
+        let stale = first.lock(workspace, "key".to_string()).await.unwrap();
+        let fence = stale.fence().unwrap();
+
+        // as if the lease expired while the first instance was stalled
+        postgres::release_lock(&pool, workspace, "key", &fence.0)
             .await
+            .unwrap();
+        let guard = second.lock(workspace, "key".to_string()).await.unwrap();
+
+        let data = serde_json::json!({});
+        assert!(matches!(
+            postgres::set_part(
+                &pool,
+                workspace,
+                "key",
+                0,
+                None,
+                &data,
+                None,
+                None,
+                Some(&fence)
+            )
+            .await,
+            Err(DbError::LeaseLost)
+        ));
+
+        let fence = guard.fence().unwrap();
+        postgres::set_part(
+            &pool,
+            workspace,
+            "key",
+            0,
+            None,
+            &data,
+            None,
+            None,
+            Some(&fence),
+        )
+        .await
+        .unwrap();
+        postgres::delete_object(&pool, workspace, "key", None, Some(&fence))
+            .await
+            .unwrap();
+
+        guard.release().await;
+        stale.release().await;
     }
 }
@@ -1,3 +1,5 @@
+use std::cmp::Ordering;
+
 use json_patch::PatchOperation as StandardPatchOperation;
 use jsonptr::{Pointer, PointerBuf};
 use serde::{Deserialize, Serialize};
@@ -41,6 +43,66 @@ pub struct RemoveOperationExt {
     pub safe: bool,
 }
 
+/// 'max' operation - sets a numeric value if the given one is greater
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MaxOperationExt {
+    // JSON Pointer to the target location (must point to a numeric value)
+    pub path: PointerBuf,
+    // Value to compare with, should be a number
+    pub value: Value,
+    // When enabled, ensures that the operation does not create new fields
+    #[serde(default)]
+    pub safe: bool,
+}
+
+/// 'min' operation - sets a numeric value if the given one is less
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MinOperationExt {
+    // JSON Pointer to the target location (must point to a numeric value)
+    pub path: PointerBuf,
+    // Value to compare with, should be a number
+    pub value: Value,
+    // When enabled, ensures that the operation does not create new fields
+    #[serde(default)]
+    pub safe: bool,
+}
+
+/// 'mul' operation - multiplies a numeric value
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MulOperationExt {
+    // JSON Pointer to the target location (must point to a numeric value)
+    pub path: PointerBuf,
+    // Factor to multiply by, should be a number
+    pub value: Value,
+    // When enabled, ensures that the operation does not create new fields
+    #[serde(default)]
+    pub safe: bool,
+}
+
+/// 'push' operation - appends a value to an array unless it is already there
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PushOperationExt {
+    // JSON Pointer to the target location (must point to an array)
+    pub path: PointerBuf,
+    // Value to append
+    pub value: Value,
+    // When enabled, ensures that the operation does not create new fields
+    #[serde(default)]
+    pub safe: bool,
+}
+
+/// 'pull' operation - removes all occurrences of a value from an array
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PullOperationExt {
+    // JSON Pointer to the target location (must point to an array)
+    pub path: PointerBuf,
+    // Value to remove
+    pub value: Value,
+    // When enabled, ensures that the operation does not fail on missing fields
+    #[serde(default)]
+    pub safe: bool,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(tag = "hop")]
 #[serde(rename_all = "lowercase")]
@@ -51,6 +113,16 @@ pub enum HulyPatchOperation {
     Inc(IncOperationExt),
     /// 'remove' operation
     Remove(RemoveOperationExt),
+    /// 'max' operation
+    Max(MaxOperationExt),
+    /// 'min' operation
+    Min(MinOperationExt),
+    /// 'mul' operation
+    Mul(MulOperationExt),
+    /// 'push' operation
+    Push(PushOperationExt),
+    /// 'pull' operation
+    Pull(PullOperationExt),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -87,13 +159,20 @@ impl<'de> serde::Deserialize<'de> for PatchOperation {
 pub enum HulyPatchError {
     #[error("invalid number")]
     InvalidNumber,
+    #[error("invalid array")]
+    InvalidArray,
     #[error("patch error: {0}")]
     PatchError(String),
+    #[error("test failed: {0}")]
+    TestFailed(String),
 }
 
 impl From<json_patch::PatchError> for HulyPatchError {
     fn from(err: json_patch::PatchError) -> Self {
-        HulyPatchError::PatchError(err.to_string())
+        match err.kind {
+            json_patch::PatchErrorKind::TestFailed => HulyPatchError::TestFailed(err.to_string()),
+            _ => HulyPatchError::PatchError(err.to_string()),
+        }
     }
 }
 
@@ -104,6 +183,15 @@ pub fn apply(doc: &mut Value, patches: &[PatchOperation]) -> Result<(), HulyPatc
                 HulyPatchOperation::Add(op) => add(doc, &op.path, &op.value, op.safe),
                 HulyPatchOperation::Inc(op) => inc(doc, &op.path, &op.value, op.safe),
                 HulyPatchOperation::Remove(op) => remove(doc, &op.path, op.safe),
+                HulyPatchOperation::Max(op) => {
+                    extremum(doc, &op.path, &op.value, op.safe, Ordering::Greater)
+                }
+                HulyPatchOperation::Min(op) => {
+                    extremum(doc, &op.path, &op.value, op.safe, Ordering::Less)
+                }
+                HulyPatchOperation::Mul(op) => mul(doc, &op.path, &op.value, op.safe),
+                HulyPatchOperation::Push(op) => push(doc, &op.path, &op.value, op.safe),
+                HulyPatchOperation::Pull(op) => pull(doc, &op.path, &op.value, op.safe),
             },
             PatchOperation::Standard(standard_op) => Ok(Some(standard_op.clone())),
         }? {
@@ -190,6 +278,157 @@ fn remove(
     })
 }
 
+// sets the value if it compares to the current one as expected, max or min
+fn extremum(
+    doc: &Value,
+    path: &Pointer,
+    value: &Value,
+    safe: bool,
+    expected: Ordering,
+) -> Result<Option<StandardPatchOperation>, HulyPatchError> {
+    let target = doc.pointer(path.as_str());
+
+    match (target, value.as_number()) {
+        (_, None) => Err(HulyPatchError::InvalidNumber),
+
+        (None, _) if safe => Ok(None),
+
+        (None, Some(value)) => Ok(Some(StandardPatchOperation::Add(
+            json_patch::AddOperation {
+                path: path.to_owned(),
+                value: Value::Number(value.to_owned()),
+            },
+        ))),
+
+        (Some(Value::Number(old_value)), Some(value)) => {
+            if compare_json_numbers(value, old_value)? == expected {
+                Ok(Some(StandardPatchOperation::Replace(
+                    json_patch::ReplaceOperation {
+                        path: path.to_owned(),
+                        value: Value::Number(value.to_owned()),
+                    },
+                )))
+            } else {
+                Ok(None)
+            }
+        }
+
+        (Some(_), Some(_)) => Err(HulyPatchError::InvalidNumber),
+    }
+}
+
+fn mul(
+    doc: &Value,
+    path: &Pointer,
+    value: &Value,
+    safe: bool,
+) -> Result<Option<StandardPatchOperation>, HulyPatchError> {
+    let target = doc.pointer(path.as_str());
+
+    match (target, value.as_number()) {
+        (_, None) => Err(HulyPatchError::InvalidNumber),
+
+        (None, _) if safe => Ok(None),
+
+        // a missing value is zero, so is the product
+        (None, Some(factor)) => {
+            let zero = if factor.is_f64() {
+                Number::from_f64(0.0).ok_or(HulyPatchError::InvalidNumber)?
+            } else {
+                Number::from(0)
+            };
+
+            Ok(Some(StandardPatchOperation::Add(
+                json_patch::AddOperation {
+                    path: path.to_owned(),
+                    value: Value::Number(zero),
+                },
+            )))
+        }
+
+        (Some(Value::Number(old_value)), Some(factor)) => {
+            let new_value = mul_json_numbers(old_value, factor)?;
+
+            Ok(Some(StandardPatchOperation::Replace(
+                json_patch::ReplaceOperation {
+                    path: path.to_owned(),
+                    value: json!(new_value),
+                },
+            )))
+        }
+
+        (Some(_), Some(_)) => Err(HulyPatchError::InvalidNumber),
+    }
+}
+
+fn push(
+    doc: &Value,
+    path: &Pointer,
+    value: &Value,
+    safe: bool,
+) -> Result<Option<StandardPatchOperation>, HulyPatchError> {
+    let target = doc.pointer(path.as_str());
+
+    match target {
+        None if safe => Ok(None),
+
+        None => Ok(Some(StandardPatchOperation::Add(
+            json_patch::AddOperation {
+                path: path.to_owned(),
+                value: json!([value]),
+            },
+        ))),
+
+        Some(Value::Array(items)) if items.contains(value) => Ok(None),
+
+        Some(Value::Array(items)) => {
+            let mut items = items.to_owned();
+            items.push(value.to_owned());
+
+            Ok(Some(StandardPatchOperation::Replace(
+                json_patch::ReplaceOperation {
+                    path: path.to_owned(),
+                    value: Value::Array(items),
+                },
+            )))
+        }
+
+        Some(_) => Err(HulyPatchError::InvalidArray),
+    }
+}
+
+fn pull(
+    doc: &Value,
+    path: &Pointer,
+    value: &Value,
+    safe: bool,
+) -> Result<Option<StandardPatchOperation>, HulyPatchError> {
+    let target = doc.pointer(path.as_str());
+
+    match target {
+        None if safe => Ok(None),
+
+        Some(Value::Array(items)) if !items.contains(value) => Ok(None),
+
+        Some(Value::Array(items)) => {
+            let items = items
+                .iter()
+                .filter(|item| *item != value)
+                .cloned()
+                .collect();
+
+            Ok(Some(StandardPatchOperation::Replace(
+                json_patch::ReplaceOperation {
+                    path: path.to_owned(),
+                    value: Value::Array(items),
+                },
+            )))
+        }
+
+        None | Some(_) => Err(HulyPatchError::InvalidArray),
+    }
+}
+
 fn add_json_numbers(a: &Number, b: &Number) -> Result<Number, HulyPatchError> {
     a.as_i64()
         .and_then(|a| b.as_i64().map(|b| Number::from(a + b)))
@@ -201,6 +440,28 @@ fn add_json_numbers(a: &Number, b: &Number) -> Result<Number, HulyPatchError> {
         .ok_or(HulyPatchError::InvalidNumber)
 }
 
+fn mul_json_numbers(a: &Number, b: &Number) -> Result<Number, HulyPatchError> {
+    a.as_i64()
+        .and_then(|a| b.as_i64().and_then(|b| a.checked_mul(b)))
+        .map(Number::from)
+        .or_else(|| {
+            a.as_f64()
+                .and_then(|a| b.as_f64().map(|b| a * b))
+                .and_then(Number::from_f64)
+        })
+        .ok_or(HulyPatchError::InvalidNumber)
+}
+
+fn compare_json_numbers(a: &Number, b: &Number) -> Result<Ordering, HulyPatchError> {
+    a.as_i64()
+        .and_then(|a| b.as_i64().map(|b| a.cmp(&b)))
+        .or_else(|| {
+            a.as_f64()
+                .and_then(|a| b.as_f64().and_then(|b| a.partial_cmp(&b)))
+        })
+        .ok_or(HulyPatchError::InvalidNumber)
+}
+
 #[cfg(test)]
 mod tests {
     use serde_json::json;
@@ -592,6 +853,21 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_patch_test_failed() {
+        let mut doc = json!({ "a": 1 });
+
+        let patches = vec![PatchOperation::Standard(StandardPatchOperation::Test(
+            json_patch::TestOperation {
+                path: PointerBuf::from_tokens(["a"]),
+                value: json!(2),
+            },
+        ))];
+
+        let res = apply(&mut doc, &patches);
+        assert!(matches!(res, Err(HulyPatchError::TestFailed(_))));
+    }
+
     #[test]
     fn test_deserialize_add_safe() {
         let patch = r#"{ "hop": "add", "path": "/a", "value": 1, "safe": true }"#;
@@ -651,4 +927,231 @@ mod tests {
             }))
         );
     }
+
+    fn apply_huly(doc: Value, op: HulyPatchOperation) -> Result<Value, HulyPatchError> {
+        let mut doc = doc;
+        apply(&mut doc, &[PatchOperation::Huly(op)])?;
+        Ok(doc)
+    }
+
+    fn max_op(path: &str, value: Value, safe: bool) -> HulyPatchOperation {
+        HulyPatchOperation::Max(MaxOperationExt {
+            path: PointerBuf::from_tokens([path]),
+            value,
+            safe,
+        })
+    }
+
+    fn min_op(path: &str, value: Value, safe: bool) -> HulyPatchOperation {
+        HulyPatchOperation::Min(MinOperationExt {
+            path: PointerBuf::from_tokens([path]),
+            value,
+            safe,
+        })
+    }
+
+    fn mul_op(path: &str, value: Value, safe: bool) -> HulyPatchOperation {
+        HulyPatchOperation::Mul(MulOperationExt {
+            path: PointerBuf::from_tokens([path]),
+            value,
+            safe,
+        })
+    }
+
+    fn push_op(path: &str, value: Value, safe: bool) -> HulyPatchOperation {
+        HulyPatchOperation::Push(PushOperationExt {
+            path: PointerBuf::from_tokens([path]),
+            value,
+            safe,
+        })
+    }
+
+    fn pull_op(path: &str, value: Value, safe: bool) -> HulyPatchOperation {
+        HulyPatchOperation::Pull(PullOperationExt {
+            path: PointerBuf::from_tokens([path]),
+            value,
+            safe,
+        })
+    }
+
+    #[test]
+    fn test_max() {
+        let test_cases = vec![
+            (
+                json!({ "a": 1 }),
+                max_op("a", json!(2), false),
+                Ok(json!({ "a": 2 })),
+            ),
+            (
+                json!({ "a": 3 }),
+                max_op("a", json!(2), false),
+                Ok(json!({ "a": 3 })),
+            ),
+            (
+                json!({ "a": 1 }),
+                max_op("a", json!(1.5), false),
+                Ok(json!({ "a": 1.5 })),
+            ),
+            (
+                json!({}),
+                max_op("a", json!(2), false),
+                Ok(json!({ "a": 2 })),
+            ),
+            (json!({}), max_op("a", json!(2), true), Ok(json!({}))),
+            (
+                json!({ "a": "b" }),
+                max_op("a", json!(2), false),
+                Err(HulyPatchError::InvalidNumber),
+            ),
+            (
+                json!({ "a": 1 }),
+                max_op("a", json!("2"), false),
+                Err(HulyPatchError::InvalidNumber),
+            ),
+        ];
+
+        for (doc, op, expected) in test_cases {
+            assert_eq!(apply_huly(doc, op), expected);
+        }
+    }
+
+    #[test]
+    fn test_min() {
+        let test_cases = vec![
+            (
+                json!({ "a": 1 }),
+                min_op("a", json!(2), false),
+                Ok(json!({ "a": 1 })),
+            ),
+            (
+                json!({ "a": 3 }),
+                min_op("a", json!(2), false),
+                Ok(json!({ "a": 2 })),
+            ),
+            (
+                json!({}),
+                min_op("a", json!(2), false),
+                Ok(json!({ "a": 2 })),
+            ),
+            (json!({}), min_op("a", json!(2), true), Ok(json!({}))),
+        ];
+
+        for (doc, op, expected) in test_cases {
+            assert_eq!(apply_huly(doc, op), expected);
+        }
+    }
+
+    #[test]
+    fn test_mul() {
+        let test_cases = vec![
+            (
+                json!({ "a": 3 }),
+                mul_op("a", json!(2), false),
+                Ok(json!({ "a": 6 })),
+            ),
+            (
+                json!({ "a": 3 }),
+                mul_op("a", json!(0.5), false),
+                Ok(json!({ "a": 1.5 })),
+            ),
+            (
+                json!({}),
+                mul_op("a", json!(2), false),
+                Ok(json!({ "a": 0 })),
+            ),
+            (json!({}), mul_op("a", json!(2), true), Ok(json!({}))),
+            (
+                json!({ "a": [] }),
+                mul_op("a", json!(2), false),
+                Err(HulyPatchError::InvalidNumber),
+            ),
+        ];
+
+        for (doc, op, expected) in test_cases {
+            assert_eq!(apply_huly(doc, op), expected);
+        }
+    }
+
+    #[test]
+    fn test_push() {
+        let test_cases = vec![
+            (
+                json!({ "a": [1] }),
+                push_op("a", json!(2), false),
+                Ok(json!({ "a": [1, 2] })),
+            ),
+            (
+                json!({ "a": [1, 2] }),
+                push_op("a", json!(2), false),
+                Ok(json!({ "a": [1, 2] })),
+            ),
+            (
+                json!({ "a": [{ "b": 1 }] }),
+                push_op("a", json!({ "b": 1 }), false),
+                Ok(json!({ "a": [{ "b": 1 }] })),
+            ),
+            (
+                json!({}),
+                push_op("a", json!(1), false),
+                Ok(json!({ "a": [1] })),
+            ),
+            (json!({}), push_op("a", json!(1), true), Ok(json!({}))),
+            (
+                json!({ "a": 1 }),
+                push_op("a", json!(1), false),
+                Err(HulyPatchError::InvalidArray),
+            ),
+        ];
+
+        for (doc, op, expected) in test_cases {
+            assert_eq!(apply_huly(doc, op), expected);
+        }
+    }
+
+    #[test]
+    fn test_pull() {
+        let test_cases = vec![
+            (
+                json!({ "a": [1, 2, 1] }),
+                pull_op("a", json!(1), false),
+                Ok(json!({ "a": [2] })),
+            ),
+            (
+                json!({ "a": [2] }),
+                pull_op("a", json!(1), false),
+                Ok(json!({ "a": [2] })),
+            ),
+            (json!({}), pull_op("a", json!(1), true), Ok(json!({}))),
+            (
+                json!({}),
+                pull_op("a", json!(1), false),
+                Err(HulyPatchError::InvalidArray),
+            ),
+            (
+                json!({ "a": "b" }),
+                pull_op("a", json!(1), true),
+                Err(HulyPatchError::InvalidArray),
+            ),
+        ];
+
+        for (doc, op, expected) in test_cases {
+            assert_eq!(apply_huly(doc, op), expected);
+        }
+    }
+
+    #[test]
+    fn test_mul_json_numbers_overflow() {
+        let res = mul_json_numbers(&Number::from(i64::MAX), &Number::from(2));
+        assert_eq!(res, Ok(Number::from_f64(i64::MAX as f64 * 2.0).unwrap()));
+    }
+
+    #[test]
+    fn test_deserialize_push() {
+        let patch = r#"{ "hop": "push", "path": "/a", "value": "b", "safe": true }"#;
+        let res = serde_json::from_str::<PatchOperation>(patch);
+        assert_eq!(
+            res.unwrap(),
+            PatchOperation::Huly(push_op("a", json!("b"), true))
+        );
+    }
 }
@@ -0,0 +1,233 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+use actix_web::dev::{Path, ResourceDef, ServiceRequest, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::config::CONFIG;
+
+// extra claims which narrow down what a token may do
+const ACCESS_CLAIM: &str = "access";
+const PREFIX_CLAIM: &str = "prefix";
+
+#[derive(Serialize, Debug)]
+pub struct PolicyInput<'a> {
+    pub method: &'a str,
+    pub workspace: Uuid,
+    pub key: &'a str,
+    pub system: bool,
+    pub claims: Value,
+}
+
+pub trait Policy: Send + Sync {
+    fn permit(&self, input: &PolicyInput) -> anyhow::Result<bool>;
+}
+
+fn is_read(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD")
+}
+
+/// Read-only tokens have access=read, key-scoped tokens have a prefix claim.
+pub struct ClaimsPolicy;
+
+impl Policy for ClaimsPolicy {
+    fn permit(&self, input: &PolicyInput) -> anyhow::Result<bool> {
+        if input.system {
+            return Ok(true);
+        }
+
+        let claim = |name: &str| input.claims.get("extra")?.get(name)?.as_str();
+
+        if claim(ACCESS_CLAIM) == Some("read") && !is_read(input.method) {
+            return Ok(false);
+        }
+
+        if let Some(prefix) = claim(PREFIX_CLAIM)
+            && !input.key.starts_with(prefix)
+        {
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+}
+
+/// Rego policy, the rule data.main.permit decides.
+#[cfg(feature = "rego")]
+pub struct RegoPolicy {
+    engine: regorus::Engine,
+}
+
+#[cfg(feature = "rego")]
+impl RegoPolicy {
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+
+        let mut engine = regorus::Engine::new();
+        engine.add_policy(path.to_owned(), text)?;
+
+        Ok(Self { engine })
+    }
+}
+
+#[cfg(feature = "rego")]
+impl Policy for RegoPolicy {
+    fn permit(&self, input: &PolicyInput) -> anyhow::Result<bool> {
+        use std::cell::RefCell;
+
+        // evaluation needs a mutable engine, each worker thread compiles its own copy once
+        thread_local! {
+            static ENGINE: RefCell<Option<regorus::Engine>> = const { RefCell::new(None) };
+        }
+
+        ENGINE.with_borrow_mut(|engine| {
+            let engine = engine.get_or_insert_with(|| self.engine.clone());
+            engine.set_input(regorus::Value::from(serde_json::to_value(input)?));
+
+            let result = engine.eval_rule("data.main.permit".to_owned())?;
+            Ok(result == regorus::Value::Bool(true))
+        })
+    }
+}
+
+/// Every policy must permit the request.
+pub struct Policies {
+    policies: Vec<Box<dyn Policy>>,
+}
+
+impl Policies {
+    pub fn from_config() -> anyhow::Result<Self> {
+        let rego: Option<Box<dyn Policy>> = match &CONFIG.policy_file {
+            #[cfg(feature = "rego")]
+            Some(path) => Some(Box::new(RegoPolicy::from_file(path)?)),
+
+            #[cfg(not(feature = "rego"))]
+            Some(_) => anyhow::bail!("policy_file requires the rego feature"),
+
+            None => None,
+        };
+
+        let mut policies: Vec<Box<dyn Policy>> = vec![Box::new(ClaimsPolicy)];
+        policies.extend(rego);
+
+        Ok(Self { policies })
+    }
+
+    pub fn permit(&self, input: &PolicyInput) -> anyhow::Result<bool> {
+        for policy in &self.policies {
+            if !policy.permit(input)? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+static KEY_PATH: LazyLock<ResourceDef> =
+    LazyLock::new(|| ResourceDef::new("/api/{workspace}/{key:.*}"));
+
+#[derive(Deserialize)]
+struct KeyPath {
+    key: String,
+}
+
+/// Key the request operates on, a query is scoped by its prefix and an audit listing by its key.
+pub fn request_key(request: &ServiceRequest) -> String {
+    // decoded the same way as the key of the handlers' path extractor
+    let mut path = Path::new(Url::new(request.uri().clone()));
+
+    let key = if KEY_PATH.capture_match_info(&mut path) {
+        path.load::<KeyPath>()
+            .map(|path| path.key)
+            .unwrap_or_default()
+    } else {
+        String::new()
+    };
+
+    let param = match key.as_str() {
+        "_query" => Some("prefix"),
+        "_audit" => Some("key"),
+        _ => None,
+    };
+
+    if let Some(param) = param {
+        return actix_web::web::Query::<HashMap<String, String>>::from_query(
+            request.query_string(),
+        )
+        .ok()
+        .and_then(|query| query.get(param).cloned())
+        .unwrap_or_default();
+    }
+
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn claims(extra: &[(&str, &str)]) -> Value {
+        serde_json::json!({
+            "account": Uuid::nil(),
+            "workspace": Uuid::nil(),
+            "extra": extra.iter().cloned().collect::<HashMap<_, _>>(),
+        })
+    }
+
+    fn permit(claims: &Value, method: &str, key: &str) -> bool {
+        let input = PolicyInput {
+            method,
+            workspace: Uuid::nil(),
+            key,
+            system: false,
+            claims: claims.clone(),
+        };
+
+        ClaimsPolicy.permit(&input).unwrap()
+    }
+
+    #[test]
+    fn test_claims_policy() {
+        let full = claims(&[]);
+        assert!(permit(&full, "PUT", "a/b"));
+
+        let read = claims(&[(ACCESS_CLAIM, "read")]);
+        assert!(permit(&read, "GET", "a/b"));
+        assert!(permit(&read, "HEAD", "a/b"));
+        assert!(!permit(&read, "PUT", "a/b"));
+        assert!(!permit(&read, "DELETE", "a/b"));
+
+        let scoped = claims(&[(PREFIX_CLAIM, "previews/")]);
+        assert!(permit(&scoped, "PUT", "previews/a"));
+        assert!(!permit(&scoped, "GET", "contracts/a"));
+    }
+
+    #[test]
+    fn test_request_key() {
+        let request = TestRequest::get()
+            .uri("/api/ws/previews/a%20b")
+            .to_srv_request();
+        assert_eq!(request_key(&request), "previews/a b");
+
+        let request = TestRequest::get()
+            .uri("/api/ws/previews/a%2Fb%25c%2Bd+e")
+            .to_srv_request();
+        assert_eq!(request_key(&request), "previews/a/b%c+d+e");
+
+        let request = TestRequest::get()
+            .uri("/api/ws/_query?prefix=previews/&meta.kind=x")
+            .to_srv_request();
+        assert_eq!(request_key(&request), "previews/");
+
+        let request = TestRequest::get().uri("/api/ws/_query").to_srv_request();
+        assert_eq!(request_key(&request), "");
+
+        let request = TestRequest::get()
+            .uri("/api/ws/_audit?key=previews/a")
+            .to_srv_request();
+        assert_eq!(request_key(&request), "previews/a");
+    }
+}
@@ -2,12 +2,14 @@ use std::pin::Pin;
 
 use bb8_postgres::PostgresConnectionManager;
 use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use serde::de::DeserializeOwned;
 use tokio_postgres::NoTls;
 use tokio_postgres::{self as pg};
 use tracing::*;
 
 use crate::config::CONFIG;
+use crate::metrics;
 
 pub type Pool = bb8::Pool<PostgresConnectionManager<NoTls>>;
 
@@ -25,10 +27,45 @@ pub enum DbError {
     #[error(transparent)]
     Refinery(#[from] refinery::Error),
 
+    #[error("timed out waiting for the key lock")]
+    LockTimeout,
+
+    #[error("key lock lease lost")]
+    LeaseLost,
+
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
+/// Owner of the key lease the write is made under.
+#[derive(Debug, Clone)]
+pub struct Fence(pub String);
+
+// a write under a lease fails once the lease expired or another instance took the key
+async fn check_fence(
+    transaction: &pg::Transaction<'_>,
+    workspace: uuid::Uuid,
+    key: &str,
+    fence: Option<&Fence>,
+) -> anyhow::Result<(), DbError> {
+    let Some(Fence(owner)) = fence else {
+        return Ok(());
+    };
+
+    transaction
+        .query_opt(
+            r#"
+            select 1 from key_lock
+            where workspace = $1 and key = $2 and owner = $3 and expires > now()
+            for update
+            "#,
+            &[&workspace, &key, owner],
+        )
+        .await?
+        .map(|_| ())
+        .ok_or(DbError::LeaseLost)
+}
+
 pub async fn pool() -> anyhow::Result<Pool, DbError> {
     let manager = bb8_postgres::PostgresConnectionManager::new_from_stringlike(
         &CONFIG.db_connection,
@@ -89,9 +126,19 @@ pub async fn pool() -> anyhow::Result<Pool, DbError> {
 async fn get_connection(
     pool: &Pool,
 ) -> Result<bb8::PooledConnection<'_, PostgresConnectionManager<NoTls>>, DbError> {
+    let _timer = metrics::DB_POOL_DURATION.start_timer();
     Ok(pool.get().await?)
 }
 
+#[instrument(level = "debug", skip_all)]
+pub async fn ping(pool: &Pool) -> anyhow::Result<(), DbError> {
+    let connection = get_connection(pool).await?;
+
+    connection.query_one("select 1", &[]).await?;
+
+    Ok(())
+}
+
 #[instrument(level = "debug", skip_all)]
 pub async fn find_blob_by_hash(pool: &Pool, hash: &str) -> anyhow::Result<Option<String>, DbError> {
     let connection = get_connection(pool).await?;
@@ -156,7 +203,88 @@ pub async fn find_parts<T: DeserializeOwned + std::fmt::Debug>(
     Ok(parts)
 }
 
+/// Longest of the keys that exist in the workspace.
+#[instrument(level = "debug", skip_all)]
+pub async fn find_longest_key(
+    pool: &Pool,
+    workspace: uuid::Uuid,
+    keys: &[String],
+) -> anyhow::Result<Option<String>, DbError> {
+    let connection = get_connection(pool).await?;
+
+    let row = connection
+        .query_opt(
+            "select key from object where workspace = $1 and key = any($2) order by length(key) desc limit 1",
+            &[&workspace, &keys],
+        )
+        .await?;
+
+    Ok(row.map(|row| row.get("key")))
+}
+
+/// Every prefix of the key, from the empty one to the key itself, for settings made on a prefix.
+pub fn prefixes(key: &str) -> impl Iterator<Item = &str> {
+    key.char_indices()
+        .map(|(i, _)| &key[..i])
+        .chain(std::iter::once(key))
+}
+
+// like pattern matching keys that start with the prefix
+fn prefix_pattern(prefix: &str) -> String {
+    format!(
+        "{}%",
+        prefix
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    )
+}
+
+/// First parts of objects with the key prefix and containing the meta values, ordered by key,
+/// compaction keeps the numbering so the first part is not always the part 0.
+#[instrument(level = "debug", skip_all)]
+pub async fn find_objects<T: DeserializeOwned>(
+    pool: &Pool,
+    workspace: uuid::Uuid,
+    prefix: &str,
+    meta: &serde_json::Value,
+    after: Option<&str>,
+    limit: i64,
+) -> anyhow::Result<Vec<T>, DbError> {
+    let connection = get_connection(pool).await?;
+
+    let pattern = prefix_pattern(prefix);
+
+    let rows = connection
+        .query(
+            r#"
+            select distinct on (key) data from object
+            where workspace = $1 and key like $2 and data -> 'meta' @> $3 and key > $4
+            order by key, part
+            limit $5
+            "#,
+            &[
+                &workspace,
+                &pattern,
+                meta,
+                &after.unwrap_or_default(),
+                &limit,
+            ],
+        )
+        .await?;
+
+    let mut objects = Vec::with_capacity(rows.len());
+
+    for row in rows {
+        let data = row.get::<_, serde_json::Value>("data");
+        objects.push(serde_json::from_value(data)?);
+    }
+
+    Ok(objects)
+}
+
 #[instrument(level = "debug", skip_all)]
+#[allow(clippy::too_many_arguments)]
 pub async fn append_part<D: serde::Serialize>(
     pool: &Pool,
     workspace: uuid::Uuid,
@@ -164,33 +292,57 @@ pub async fn append_part<D: serde::Serialize>(
     part: u32,
     inline: Option<Bytes>,
     data: &D,
+    audit: Option<&AuditRecord<'_>>,
+    fence: Option<&Fence>,
 ) -> anyhow::Result<(), DbError> {
-    let connection = get_connection(pool).await?;
+    let mut connection = get_connection(pool).await?;
+
+    let transaction = connection.transaction().await?;
+    check_fence(&transaction, workspace, key, fence).await?;
 
     let data = serde_json::to_value(data)?;
     let inline = inline.map(|b| b.to_vec());
 
-    connection
+    transaction
         .execute(
             "insert into object (workspace, key, part, inline, data) values ($1, $2, $3, $4, $5)",
             &[&workspace, &key, &(part as i32), &inline, &data],
         )
         .await?;
 
+    if let Some(audit) = audit {
+        insert_audit(&transaction, workspace, key, audit).await?;
+    }
+
+    transaction.commit().await?;
+
     Ok(())
 }
 
+/// Expiry of a new object, the one of the replaced object does not carry over, and whether the
+/// key becomes immutable.
+pub struct ObjectState {
+    pub expires: Option<DateTime<Utc>>,
+    pub immutable: bool,
+}
+
 #[instrument(level = "debug", skip_all)]
+#[allow(clippy::too_many_arguments)]
 pub async fn set_part<D: serde::Serialize>(
     pool: &Pool,
     workspace: uuid::Uuid,
     key: &str,
+    part: u32,
     inline: Option<Bytes>,
     data: &D,
+    state: Option<&ObjectState>,
+    audit: Option<&AuditRecord<'_>>,
+    fence: Option<&Fence>,
 ) -> anyhow::Result<(), DbError> {
     let mut connection = get_connection(pool).await?;
 
     let transaction = connection.transaction().await?;
+    check_fence(&transaction, workspace, key, fence).await?;
 
     transaction
         .execute(
@@ -205,16 +357,768 @@ pub async fn set_part<D: serde::Serialize>(
     transaction
         .execute(
             r#"
-            insert into object (workspace, key, part, inline, data) values ($1, $2, 0, $3, $4)
+            insert into object (workspace, key, part, inline, data) values ($1, $2, $3, $4, $5)
             on conflict (workspace, key, part) do update set
-                inline = $3, 
-                data = $4
+                inline = $4,
+                data = $5
             "#,
-            &[&workspace, &key, &inline, &data],
+            &[&workspace, &key, &(part as i32), &inline, &data],
         )
         .await?;
 
+    if let Some(state) = state {
+        set_expiry(&transaction, workspace, key, state.expires).await?;
+
+        if state.immutable {
+            set_immutable(&transaction, workspace, key).await?;
+        }
+    }
+
+    if let Some(audit) = audit {
+        insert_audit(&transaction, workspace, key, audit).await?;
+    }
+
     transaction.commit().await?;
 
     Ok(())
 }
+
+/// Replaces data of existing parts, keeping their content.
+#[instrument(level = "debug", skip_all)]
+pub async fn update_parts<D: serde::Serialize>(
+    pool: &Pool,
+    workspace: uuid::Uuid,
+    key: &str,
+    parts: &[(u32, &D)],
+    audit: Option<&AuditRecord<'_>>,
+    fence: Option<&Fence>,
+) -> anyhow::Result<(), DbError> {
+    let mut connection = get_connection(pool).await?;
+
+    let transaction = connection.transaction().await?;
+    check_fence(&transaction, workspace, key, fence).await?;
+
+    for (part, data) in parts {
+        let data = serde_json::to_value(data)?;
+
+        transaction
+            .execute(
+                "update object set data = $4 where workspace = $1 and key = $2 and part = $3",
+                &[&workspace, &key, &(*part as i32), &data],
+            )
+            .await?;
+    }
+
+    if let Some(audit) = audit {
+        insert_audit(&transaction, workspace, key, audit).await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Removes the parts after the given one and writes it anew, a rewritten compacted part
+/// may hold fewer parts under a lower number.
+#[instrument(level = "debug", skip_all)]
+#[allow(clippy::too_many_arguments)]
+pub async fn truncate_parts<D: serde::Serialize>(
+    pool: &Pool,
+    workspace: uuid::Uuid,
+    key: &str,
+    part: u32,
+    inline: Option<Bytes>,
+    data: &D,
+    audit: Option<&AuditRecord<'_>>,
+    fence: Option<&Fence>,
+) -> anyhow::Result<(), DbError> {
+    let mut connection = get_connection(pool).await?;
+
+    let transaction = connection.transaction().await?;
+    check_fence(&transaction, workspace, key, fence).await?;
+
+    let data = serde_json::to_value(data)?;
+    let inline = inline.map(|b| b.to_vec());
+
+    transaction
+        .execute(
+            "delete from object where workspace = $1 and key = $2 and part >= $3",
+            &[&workspace, &key, &(part as i32)],
+        )
+        .await?;
+
+    transaction
+        .execute(
+            "insert into object (workspace, key, part, inline, data) values ($1, $2, $3, $4, $5)",
+            &[&workspace, &key, &(part as i32), &inline, &data],
+        )
+        .await?;
+
+    if let Some(audit) = audit {
+        insert_audit(&transaction, workspace, key, audit).await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Removes all parts of the object, returns false if there were none.
+#[instrument(level = "debug", skip_all)]
+pub async fn delete_object(
+    pool: &Pool,
+    workspace: uuid::Uuid,
+    key: &str,
+    audit: Option<&AuditRecord<'_>>,
+    fence: Option<&Fence>,
+) -> anyhow::Result<bool, DbError> {
+    let mut connection = get_connection(pool).await?;
+
+    let transaction = connection.transaction().await?;
+    check_fence(&transaction, workspace, key, fence).await?;
+
+    let deleted = transaction
+        .execute(
+            "delete from object where workspace = $1 and key = $2",
+            &[&workspace, &key],
+        )
+        .await?;
+
+    transaction
+        .execute(
+            "delete from object_expiry where workspace = $1 and key = $2",
+            &[&workspace, &key],
+        )
+        .await?;
+
+    if let Some(audit) = audit {
+        insert_audit(&transaction, workspace, key, audit).await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(deleted > 0)
+}
+
+async fn set_expiry(
+    transaction: &pg::Transaction<'_>,
+    workspace: uuid::Uuid,
+    key: &str,
+    expires: Option<DateTime<Utc>>,
+) -> anyhow::Result<(), DbError> {
+    match expires {
+        Some(expires) => {
+            transaction
+                .execute(
+                    r#"
+                    insert into object_expiry (workspace, key, expires) values ($1, $2, $3)
+                    on conflict (workspace, key) do update set expires = $3
+                    "#,
+                    &[&workspace, &key, &expires],
+                )
+                .await?
+        }
+        None => {
+            transaction
+                .execute(
+                    "delete from object_expiry where workspace = $1 and key = $2",
+                    &[&workspace, &key],
+                )
+                .await?
+        }
+    };
+
+    Ok(())
+}
+
+#[instrument(level = "debug", skip_all)]
+pub async fn find_expiry(
+    pool: &Pool,
+    workspace: uuid::Uuid,
+    key: &str,
+) -> anyhow::Result<Option<DateTime<Utc>>, DbError> {
+    let connection = get_connection(pool).await?;
+
+    let row = connection
+        .query_opt(
+            "select expires from object_expiry where workspace = $1 and key = $2",
+            &[&workspace, &key],
+        )
+        .await?;
+
+    Ok(row.map(|row| row.get("expires")))
+}
+
+#[instrument(level = "debug", skip_all)]
+pub async fn find_expired_objects(
+    pool: &Pool,
+    limit: i64,
+) -> anyhow::Result<Vec<(uuid::Uuid, String)>, DbError> {
+    let connection = get_connection(pool).await?;
+
+    let rows = connection
+        .query(
+            &format!(
+                r#"
+                select workspace, key from object_expiry o
+                where expires <= now() and not {RETAINED}
+                order by expires
+                limit $1
+                "#
+            ),
+            &[&limit],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.get("workspace"), row.get("key")))
+        .collect())
+}
+
+/// Workspaces which have an object with the key.
+#[instrument(level = "debug", skip_all)]
+pub async fn find_workspaces_with_key(
+    pool: &Pool,
+    key: &str,
+) -> anyhow::Result<Vec<uuid::Uuid>, DbError> {
+    let connection = get_connection(pool).await?;
+
+    let rows = connection
+        .query(
+            "select distinct workspace from object where key = $1",
+            &[&key],
+        )
+        .await?;
+
+    Ok(rows.into_iter().map(|row| row.get("workspace")).collect())
+}
+
+// matches when the key of the o row is immutable or held, by itself or by a prefix
+const RETAINED: &str = r#"
+    exists (
+        select 1 from object_retention r
+        where r.workspace = o.workspace and (r.immutable or r.legal_hold)
+            and (r.key = o.key or (r.prefix and left(o.key, length(r.key)) = r.key))
+    )
+"#;
+
+/// Keys with the prefix not written since the date and after the given one, ordered by key,
+/// reserved and retained keys are left out.
+#[instrument(level = "debug", skip_all)]
+pub async fn find_stale_objects(
+    pool: &Pool,
+    workspace: uuid::Uuid,
+    prefix: &str,
+    before: DateTime<Utc>,
+    reserved: &[&str],
+    after: &str,
+    limit: i64,
+) -> anyhow::Result<Vec<String>, DbError> {
+    let connection = get_connection(pool).await?;
+
+    let reserved = reserved
+        .iter()
+        .map(|prefix| prefix_pattern(prefix))
+        .collect::<Vec<_>>();
+
+    let rows = connection
+        .query(
+            &format!(
+                r#"
+                select key from object o
+                where workspace = $1 and key like $2 and key > $5 and not (key like any($4))
+                    and not {RETAINED}
+                group by key
+                having max((data ->> 'date')::timestamptz) < $3
+                order by key
+                limit $6
+                "#
+            ),
+            &[
+                &workspace,
+                &prefix_pattern(prefix),
+                &before,
+                &reserved,
+                &after,
+                &limit,
+            ],
+        )
+        .await?;
+
+    Ok(rows.into_iter().map(|row| row.get("key")).collect())
+}
+
+/// Immutability and legal hold of the key, set on the key itself or on any of the prefixes.
+#[instrument(level = "debug", skip_all)]
+pub async fn find_retention(
+    pool: &Pool,
+    workspace: uuid::Uuid,
+    key: &str,
+    prefixes: &[String],
+) -> anyhow::Result<(bool, bool), DbError> {
+    let connection = get_connection(pool).await?;
+
+    let row = connection
+        .query_one(
+            r#"
+            select
+                coalesce(bool_or(immutable), false) as immutable,
+                coalesce(bool_or(legal_hold), false) as legal_hold
+            from object_retention
+            where workspace = $1 and ((not prefix and key = $2) or (prefix and key = any($3)))
+            "#,
+            &[&workspace, &key, &prefixes],
+        )
+        .await?;
+
+    Ok((row.get("immutable"), row.get("legal_hold")))
+}
+
+/// Sets retention of a key or prefix, immutability once set is never cleared.
+#[instrument(level = "debug", skip_all)]
+pub async fn set_retention(
+    pool: &Pool,
+    workspace: uuid::Uuid,
+    key: &str,
+    prefix: bool,
+    immutable: bool,
+    legal_hold: bool,
+) -> anyhow::Result<(), DbError> {
+    let connection = get_connection(pool).await?;
+
+    connection
+        .execute(
+            r#"
+            insert into object_retention (workspace, key, prefix, immutable, legal_hold)
+            values ($1, $2, $3, $4, $5)
+            on conflict (workspace, key, prefix) do update set
+                immutable = object_retention.immutable or excluded.immutable,
+                legal_hold = excluded.legal_hold
+            "#,
+            &[&workspace, &key, &prefix, &immutable, &legal_hold],
+        )
+        .await?;
+
+    Ok(())
+}
+
+// makes the key immutable, keeping its legal hold
+async fn set_immutable(
+    transaction: &pg::Transaction<'_>,
+    workspace: uuid::Uuid,
+    key: &str,
+) -> anyhow::Result<(), DbError> {
+    transaction
+        .execute(
+            r#"
+            insert into object_retention (workspace, key, prefix, immutable, legal_hold)
+            values ($1, $2, false, true, false)
+            on conflict (workspace, key, prefix) do update set immutable = true
+            "#,
+            &[&workspace, &key],
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[instrument(level = "debug", skip_all)]
+pub async fn list_retention(
+    pool: &Pool,
+    workspace: uuid::Uuid,
+) -> anyhow::Result<Vec<(String, bool, bool, bool)>, DbError> {
+    let connection = get_connection(pool).await?;
+
+    let rows = connection
+        .query(
+            "select key, prefix, immutable, legal_hold from object_retention where workspace = $1 order by key",
+            &[&workspace],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            (
+                row.get("key"),
+                row.get("prefix"),
+                row.get("immutable"),
+                row.get("legal_hold"),
+            )
+        })
+        .collect())
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub key: String,
+    pub action: String,
+    pub part: Option<i32>,
+    pub etag: Option<String>,
+    pub writer: Option<String>,
+    pub date: DateTime<Utc>,
+}
+
+/// Audit entry of a mutation, written in the same transaction.
+#[derive(Debug)]
+pub struct AuditRecord<'a> {
+    pub action: &'static str,
+    pub part: Option<u32>,
+    pub etag: Option<&'a str>,
+    pub writer: Option<&'a str>,
+}
+
+async fn insert_audit(
+    transaction: &pg::Transaction<'_>,
+    workspace: uuid::Uuid,
+    key: &str,
+    audit: &AuditRecord<'_>,
+) -> anyhow::Result<(), DbError> {
+    transaction
+        .execute(
+            r#"
+            insert into audit_log (workspace, key, action, part, etag, writer)
+            values ($1, $2, $3, $4, $5, $6)
+            "#,
+            &[
+                &workspace,
+                &key,
+                &audit.action,
+                &audit.part.map(|part| part as i32),
+                &audit.etag,
+                &audit.writer,
+            ],
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Audit entries of the workspace or a key, the newest first.
+#[instrument(level = "debug", skip_all)]
+pub async fn find_audit(
+    pool: &Pool,
+    workspace: uuid::Uuid,
+    key: Option<&str>,
+    before: Option<i64>,
+    limit: i64,
+) -> anyhow::Result<Vec<AuditEntry>, DbError> {
+    let connection = get_connection(pool).await?;
+
+    let rows = connection
+        .query(
+            r#"
+            select id, key, action, part, etag, writer, date from audit_log
+            where workspace = $1
+                and ($2::text is null or key = $2)
+                and ($3::int8 is null or (date, id) < (
+                    select date, id from audit_log where workspace = $1 and id = $3
+                ))
+            order by date desc, id desc
+            limit $4
+            "#,
+            &[&workspace, &key, &before, &limit],
+        )
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| AuditEntry {
+            id: row.get("id"),
+            key: row.get("key"),
+            action: row.get("action"),
+            part: row.get("part"),
+            etag: row.get("etag"),
+            writer: row.get("writer"),
+            date: row.get("date"),
+        })
+        .collect())
+}
+
+#[instrument(level = "debug", skip_all)]
+pub async fn save_compact_tasks(
+    pool: &Pool,
+    tasks: &[(uuid::Uuid, String)],
+) -> anyhow::Result<(), DbError> {
+    let mut connection = get_connection(pool).await?;
+
+    let transaction = connection.transaction().await?;
+
+    for (workspace, key) in tasks {
+        transaction
+            .execute(
+                "insert into compact_task (workspace, key) values ($1, $2) on conflict do nothing",
+                &[workspace, key],
+            )
+            .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+#[instrument(level = "debug", skip_all)]
+pub async fn take_compact_tasks(pool: &Pool) -> anyhow::Result<Vec<(uuid::Uuid, String)>, DbError> {
+    let connection = get_connection(pool).await?;
+
+    let rows = connection
+        .query("delete from compact_task returning workspace, key", &[])
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.get("workspace"), row.get("key")))
+        .collect())
+}
+
+fn interval_ms(duration: std::time::Duration) -> f64 {
+    duration.as_millis() as f64
+}
+
+/// Takes the lease on a key unless another owner holds an unexpired one.
+#[instrument(level = "debug", skip_all)]
+pub async fn acquire_lock(
+    pool: &Pool,
+    workspace: uuid::Uuid,
+    key: &str,
+    owner: &str,
+    ttl: std::time::Duration,
+) -> anyhow::Result<bool, DbError> {
+    let connection = get_connection(pool).await?;
+
+    let acquired = connection
+        .execute(
+            r#"
+            insert into key_lock (workspace, key, owner, expires)
+            values ($1, $2, $3, now() + interval '1 millisecond' * $4::float8)
+            on conflict (workspace, key) do update
+                set owner = excluded.owner, expires = excluded.expires
+                where key_lock.expires < now()
+            "#,
+            &[&workspace, &key, &owner, &interval_ms(ttl)],
+        )
+        .await?;
+
+    Ok(acquired > 0)
+}
+
+#[instrument(level = "debug", skip_all)]
+pub async fn renew_lock(
+    pool: &Pool,
+    workspace: uuid::Uuid,
+    key: &str,
+    owner: &str,
+    ttl: std::time::Duration,
+) -> anyhow::Result<bool, DbError> {
+    let connection = get_connection(pool).await?;
+
+    let renewed = connection
+        .execute(
+            r#"
+            update key_lock set expires = now() + interval '1 millisecond' * $4::float8
+            where workspace = $1 and key = $2 and owner = $3
+            "#,
+            &[&workspace, &key, &owner, &interval_ms(ttl)],
+        )
+        .await?;
+
+    Ok(renewed > 0)
+}
+
+#[instrument(level = "debug", skip_all)]
+pub async fn release_lock(
+    pool: &Pool,
+    workspace: uuid::Uuid,
+    key: &str,
+    owner: &str,
+) -> anyhow::Result<(), DbError> {
+    let connection = get_connection(pool).await?;
+
+    connection
+        .execute(
+            "delete from key_lock where workspace = $1 and key = $2 and owner = $3",
+            &[&workspace, &key, &owner],
+        )
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_stale_objects() {
+        let pool = pool().await.unwrap();
+        let workspace = uuid::Uuid::new_v4();
+        let data = serde_json::json!({ "date": "2000-01-01T00:00:00Z" });
+
+        for key in ["_lifecycle", "a", "b/held", "c"] {
+            set_part(&pool, workspace, key, 0, None, &data, None, None, None)
+                .await
+                .unwrap();
+        }
+        set_retention(&pool, workspace, "b/", true, false, true)
+            .await
+            .unwrap();
+
+        let mut keys = Vec::new();
+        let mut after = String::new();
+        loop {
+            let page =
+                find_stale_objects(&pool, workspace, "", Utc::now(), &["_lifecycle"], &after, 1)
+                    .await
+                    .unwrap();
+            let Some(last) = page.last() else {
+                break;
+            };
+            after = last.clone();
+            keys.extend(page);
+        }
+
+        assert_eq!(keys, vec!["a", "c"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_audit() {
+        let pool = pool().await.unwrap();
+        let workspace = uuid::Uuid::new_v4();
+        let data = serde_json::json!({});
+
+        let audit = |action| AuditRecord {
+            action,
+            part: None,
+            etag: None,
+            writer: None,
+        };
+
+        set_part(
+            &pool,
+            workspace,
+            "a",
+            0,
+            None,
+            &data,
+            None,
+            Some(&audit("put")),
+            None,
+        )
+        .await
+        .unwrap();
+        append_part(
+            &pool,
+            workspace,
+            "a",
+            1,
+            None,
+            &data,
+            Some(&audit("patch")),
+            None,
+        )
+        .await
+        .unwrap();
+
+        // a failed mutation leaves no entry
+        let fence = Fence("stale".to_string());
+        assert!(matches!(
+            delete_object(&pool, workspace, "a", Some(&audit("delete")), Some(&fence)).await,
+            Err(DbError::LeaseLost)
+        ));
+
+        delete_object(&pool, workspace, "a", Some(&audit("delete")), None)
+            .await
+            .unwrap();
+
+        let mut actions = Vec::new();
+        let mut before = None;
+        loop {
+            let page = find_audit(&pool, workspace, Some("a"), before, 1)
+                .await
+                .unwrap();
+            let Some(last) = page.last() else {
+                break;
+            };
+            before = Some(last.id);
+            actions.extend(page.into_iter().map(|entry| entry.action));
+        }
+
+        assert_eq!(actions, vec!["delete", "patch", "put"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_retained_expiry() {
+        let pool = pool().await.unwrap();
+        let workspace = uuid::Uuid::new_v4();
+        let data = serde_json::json!({});
+        let state = ObjectState {
+            expires: Some(Utc::now() - chrono::Duration::days(1)),
+            immutable: false,
+        };
+
+        for key in ["a", "b"] {
+            set_part(
+                &pool,
+                workspace,
+                key,
+                0,
+                None,
+                &data,
+                Some(&state),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        set_retention(&pool, workspace, "a", false, false, true)
+            .await
+            .unwrap();
+
+        // put with huly-immutable keeps the hold
+        let state = ObjectState {
+            immutable: true,
+            ..state
+        };
+        set_part(
+            &pool,
+            workspace,
+            "a",
+            0,
+            None,
+            &data,
+            Some(&state),
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            find_retention(&pool, workspace, "a", &[]).await.unwrap(),
+            (true, true)
+        );
+
+        let keys = find_expired_objects(&pool, i64::MAX)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|(expired, _)| *expired == workspace)
+            .map(|(_, key)| key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!["b"]);
+    }
+
+    #[test]
+    fn test_prefixes() {
+        assert_eq!(
+            prefixes("a/b").collect::<Vec<_>>(),
+            vec!["", "a", "a/", "a/b"]
+        );
+    }
+}
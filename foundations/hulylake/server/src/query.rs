@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use actix_web::{
+    HttpResponse,
+    error::ErrorBadRequest,
+    web::{Data, Path, Query},
+};
+use serde::Serialize;
+use tracing::*;
+use uuid::Uuid;
+
+use crate::handlers::{HandlerResult, PartData};
+use crate::merge::MergeStrategy;
+use crate::postgres::{self, Pool};
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ObjectQuery {
+    pub prefix: String,
+    pub meta: HashMap<String, String>,
+    pub after: Option<String>,
+    pub limit: i64,
+}
+
+impl ObjectQuery {
+    // meta values are given as meta.<name>=<value>
+    pub fn parse(params: Vec<(String, String)>) -> HandlerResult<Self> {
+        let mut query = ObjectQuery {
+            prefix: String::new(),
+            meta: HashMap::new(),
+            after: None,
+            limit: DEFAULT_LIMIT,
+        };
+
+        for (name, value) in params {
+            match name.as_str() {
+                "prefix" => query.prefix = value,
+                "after" => query.after = Some(value),
+                "limit" => {
+                    query.limit = value
+                        .parse::<i64>()
+                        .ok()
+                        .filter(|limit| (1..=MAX_LIMIT).contains(limit))
+                        .ok_or_else(|| ErrorBadRequest(format!("invalid limit: {value}")))?;
+                }
+                _ => match name.strip_prefix("meta.") {
+                    Some(meta) if !meta.is_empty() => {
+                        query.meta.insert(meta.to_lowercase(), value);
+                    }
+                    _ => return Err(ErrorBadRequest(format!("unknown parameter: {name}")).into()),
+                },
+            }
+        }
+
+        Ok(query)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ObjectEntry {
+    pub key: String,
+    pub merge_strategy: Option<MergeStrategy>,
+    pub headers: HashMap<String, String>,
+    pub meta: HashMap<String, String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct QueryResult {
+    pub objects: Vec<ObjectEntry>,
+
+    // key to pass as after for the next page, absent on the last page
+    pub next: Option<String>,
+}
+
+#[instrument(level = "debug", skip_all, fields(workspace))]
+pub async fn find(
+    workspace: Path<Uuid>,
+    params: Query<Vec<(String, String)>>,
+    pool: Data<Pool>,
+) -> HandlerResult<HttpResponse> {
+    let workspace = workspace.into_inner();
+    Span::current().record("workspace", workspace.to_string());
+
+    let query = ObjectQuery::parse(params.into_inner())?;
+
+    // one more to know whether there is a next page
+    let mut parts = postgres::find_objects::<PartData>(
+        &pool,
+        workspace,
+        &query.prefix,
+        &serde_json::to_value(&query.meta).unwrap(),
+        query.after.as_deref(),
+        query.limit + 1,
+    )
+    .await?;
+
+    let next = if parts.len() as i64 > query.limit {
+        parts.truncate(query.limit as usize);
+        parts.last().map(|part| part.key.clone())
+    } else {
+        None
+    };
+
+    let objects = parts
+        .into_iter()
+        .map(|part| ObjectEntry {
+            key: part.key,
+            merge_strategy: part.merge_strategy,
+            headers: part.headers.unwrap_or_default(),
+            meta: part.meta.unwrap_or_default(),
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(QueryResult { objects, next }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(params: &[(&str, &str)]) -> Vec<(String, String)> {
+        params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let query = ObjectQuery::parse(params(&[
+            ("meta.Kind", "avatar"),
+            ("prefix", "users/"),
+            ("after", "users/a"),
+            ("limit", "10"),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            query,
+            ObjectQuery {
+                prefix: "users/".to_string(),
+                meta: HashMap::from([("kind".to_string(), "avatar".to_string())]),
+                after: Some("users/a".to_string()),
+                limit: 10,
+            }
+        );
+
+        assert_eq!(ObjectQuery::parse(vec![]).unwrap().limit, DEFAULT_LIMIT);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(ObjectQuery::parse(params(&[("limit", "0")])).is_err());
+        assert!(ObjectQuery::parse(params(&[("limit", "100000")])).is_err());
+        assert!(ObjectQuery::parse(params(&[("meta.", "x")])).is_err());
+        assert!(ObjectQuery::parse(params(&[("kind", "x")])).is_err());
+    }
+}
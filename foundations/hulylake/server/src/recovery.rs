@@ -1,9 +1,13 @@
 use aws_sdk_s3::error::SdkError;
 use aws_sdk_s3::operation::put_object::PutObjectError;
+use base64::prelude::*;
 use bytes::Bytes;
+use serde::Serialize;
 
 use crate::conditional::ConditionalMatch;
 use crate::config::CONFIG;
+use crate::metrics;
+use crate::postgres::ObjectPart;
 use crate::{handlers::PartData, s3::S3Client};
 
 #[derive(thiserror::Error, Debug)]
@@ -35,7 +39,37 @@ impl From<SdkError<PutObjectError>> for RecoveryError {
     }
 }
 
-pub fn object_etag(parts: Vec<&PartData>) -> anyhow::Result<String> {
+/// Part as written to the manifest, a part which is not in s3 carries its content,
+/// so that it can be recovered.
+#[derive(Serialize, Debug)]
+pub struct ManifestPart<'a> {
+    #[serde(flatten)]
+    data: &'a PartData,
+
+    // base64
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+}
+
+impl<'a> ManifestPart<'a> {
+    pub fn new(data: &'a PartData, inline: Option<&[u8]>) -> Self {
+        // inline only blobs have neither an s3 object nor chunks
+        let content = match (&data.blob, &data.chunks, inline) {
+            (None, None, Some(inline)) => Some(BASE64_STANDARD.encode(inline)),
+            _ => None,
+        };
+
+        Self { data, content }
+    }
+}
+
+impl<'a> From<&'a ObjectPart<PartData>> for ManifestPart<'a> {
+    fn from(part: &'a ObjectPart<PartData>) -> Self {
+        Self::new(&part.data, part.inline.as_deref())
+    }
+}
+
+pub fn object_etag(parts: Vec<ManifestPart>) -> anyhow::Result<String> {
     let body = Bytes::from(serde_json::to_string(&parts)?);
     let digest = md5::compute(body);
     Ok(format!("{:x}", digest))
@@ -46,7 +80,7 @@ pub async fn set_object(
     s3: &S3Client,
     workspace: uuid::Uuid,
     key: &str,
-    parts: Vec<&PartData>,
+    parts: Vec<ManifestPart<'_>>,
     conditions: Option<ConditionalMatch>,
 ) -> Result<(), RecoveryError> {
     let s3_bucket = &CONFIG.s3_bucket;
@@ -67,7 +101,27 @@ pub async fn set_object(
         None => cmd,
     };
 
-    cmd.send().await?;
+    metrics::s3("put_object", cmd.send()).await?;
+
+    Ok(())
+}
+
+#[tracing::instrument(level = "debug", skip_all)]
+pub async fn delete_object(
+    s3: &S3Client,
+    workspace: uuid::Uuid,
+    key: &str,
+) -> Result<(), RecoveryError> {
+    let s3_bucket = &CONFIG.s3_bucket;
+
+    let key = format!("blob/{}/{}", workspace, key);
+
+    metrics::s3(
+        "delete_object",
+        s3.delete_object().bucket(s3_bucket).key(key).send(),
+    )
+    .await
+    .map_err(|error| RecoveryError::S3(error.to_string()))?;
 
     Ok(())
 }
@@ -79,13 +133,57 @@ pub async fn set_blob(s3: &S3Client, key: &str, hash: &str) -> Result<(), Recove
     let key = format!("hash/{}", key);
     let body = Bytes::from(hash.to_string());
 
-    s3.put_object()
-        .bucket(s3_bucket)
-        .key(key)
-        .body(body.into())
-        .content_type("text/plain")
-        .send()
-        .await?;
+    metrics::s3(
+        "put_object",
+        s3.put_object()
+            .bucket(s3_bucket)
+            .key(key)
+            .body(body.into())
+            .content_type("text/plain")
+            .send(),
+    )
+    .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_part() {
+        let mut data = PartData {
+            workspace: uuid::Uuid::nil(),
+            key: "test".to_string(),
+            part: 0,
+            size: 5,
+            blob: None,
+            etag: "etag".to_string(),
+            date: chrono::Utc::now(),
+            headers: None,
+            meta: None,
+            merge_strategy: None,
+            chunks: None,
+            segments: None,
+            writer: None,
+        };
+
+        let manifest = serde_json::to_value(ManifestPart::new(&data, Some(b"hello"))).unwrap();
+        assert_eq!(manifest["content"], "aGVsbG8=");
+        assert_eq!(manifest["etag"], "etag");
+
+        // the database copy of the part does not carry the content
+        assert!(
+            serde_json::to_value(&data)
+                .unwrap()
+                .get("content")
+                .is_none()
+        );
+
+        // read from s3
+        data.blob = Some("blob".to_string());
+        let manifest = serde_json::to_value(ManifestPart::new(&data, Some(b"hello"))).unwrap();
+        assert!(manifest.get("content").is_none());
+    }
+}
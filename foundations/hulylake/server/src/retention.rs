@@ -0,0 +1,181 @@
+use actix_web::{
+    HttpResponse,
+    web::{Data, Json, Path, ReqData},
+};
+use hulyrs::services::jwt::Claims;
+use serde::{Deserialize, Serialize};
+use tracing::*;
+use uuid::Uuid;
+
+use crate::handlers::HandlerResult;
+use crate::postgres::{self, DbError, Pool};
+
+#[derive(thiserror::Error, Debug)]
+pub enum RetentionError {
+    #[error("{key} is {reason} and cannot be modified")]
+    Modify { key: String, reason: &'static str },
+
+    #[error("{key} is {reason} and cannot be deleted")]
+    Delete { key: String, reason: &'static str },
+
+    #[error("retention can only be changed with a system token")]
+    Forbidden,
+}
+
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub immutable: bool,
+    pub legal_hold: bool,
+}
+
+impl Retention {
+    fn reason(&self) -> Option<&'static str> {
+        if self.immutable {
+            Some("immutable")
+        } else if self.legal_hold {
+            Some("under legal hold")
+        } else {
+            None
+        }
+    }
+
+    /// Appending parts or changing metadata, allowed under legal hold.
+    pub fn check_append(&self, key: &str) -> Result<(), RetentionError> {
+        match self.immutable {
+            true => Err(RetentionError::Modify {
+                key: key.to_owned(),
+                reason: "immutable",
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// Replacing the content, which removes the previous one.
+    pub fn check_overwrite(&self, key: &str) -> Result<(), RetentionError> {
+        match self.reason() {
+            Some(reason) => Err(RetentionError::Modify {
+                key: key.to_owned(),
+                reason,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    pub fn check_delete(&self, key: &str) -> Result<(), RetentionError> {
+        match self.reason() {
+            Some(reason) => Err(RetentionError::Delete {
+                key: key.to_owned(),
+                reason,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+pub async fn find(pool: &Pool, workspace: Uuid, key: &str) -> Result<Retention, DbError> {
+    let prefixes = postgres::prefixes(key)
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    let (immutable, legal_hold) = postgres::find_retention(pool, workspace, key, &prefixes).await?;
+
+    Ok(Retention {
+        immutable,
+        legal_hold,
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct RetentionRule {
+    pub key: String,
+
+    // the rule applies to all keys starting with key
+    #[serde(default)]
+    pub prefix: bool,
+
+    #[serde(default)]
+    pub immutable: bool,
+
+    #[serde(default)]
+    pub legal_hold: bool,
+}
+
+pub async fn list(workspace: Path<Uuid>, pool: Data<Pool>) -> HandlerResult<HttpResponse> {
+    let rules = postgres::list_retention(&pool, workspace.into_inner())
+        .await?
+        .into_iter()
+        .map(|(key, prefix, immutable, legal_hold)| RetentionRule {
+            key,
+            prefix,
+            immutable,
+            legal_hold,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(HttpResponse::Ok().json(rules))
+}
+
+#[instrument(level = "debug", skip_all, fields(workspace))]
+pub async fn set(
+    workspace: Path<Uuid>,
+    claims: ReqData<Claims>,
+    rule: Json<RetentionRule>,
+    pool: Data<Pool>,
+) -> HandlerResult<HttpResponse> {
+    let workspace = workspace.into_inner();
+    Span::current().record("workspace", workspace.to_string());
+
+    if !claims.is_system() {
+        return Err(RetentionError::Forbidden.into());
+    }
+
+    let rule = rule.into_inner();
+
+    postgres::set_retention(
+        &pool,
+        workspace,
+        &rule.key,
+        rule.prefix,
+        rule.immutable,
+        rule.legal_hold,
+    )
+    .await?;
+
+    info!(?rule, "retention changed");
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checks() {
+        let none = Retention::default();
+        assert!(none.check_append("k").is_ok());
+        assert!(none.check_overwrite("k").is_ok());
+        assert!(none.check_delete("k").is_ok());
+
+        let immutable = Retention {
+            immutable: true,
+            legal_hold: false,
+        };
+        assert!(immutable.check_append("k").is_err());
+        assert!(immutable.check_overwrite("k").is_err());
+        assert!(immutable.check_delete("k").is_err());
+
+        let legal_hold = Retention {
+            immutable: false,
+            legal_hold: true,
+        };
+        assert!(legal_hold.check_append("k").is_ok());
+        assert!(legal_hold.check_overwrite("k").is_err());
+        assert!(matches!(
+            legal_hold.check_delete("k"),
+            Err(RetentionError::Delete {
+                reason: "under legal hold",
+                ..
+            })
+        ));
+    }
+}
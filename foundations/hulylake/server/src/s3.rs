@@ -12,6 +12,8 @@ use futures::stream::StreamExt;
 use futures_util::Stream;
 use tracing::*;
 
+use crate::metrics;
+
 pub type S3Client = aws_sdk_s3::Client;
 
 pub async fn client() -> S3Client {
@@ -49,15 +51,17 @@ where
     debug!("upload start");
 
     let upload_part = async |number, buffer: Bytes| -> Result<CompletedPart> {
-        let upload = s3
-            .upload_part()
-            .bucket(bucket)
-            .key(key)
-            .upload_id(upload_id)
-            .body(buffer.into())
-            .part_number(number)
-            .send()
-            .await?;
+        let upload = metrics::s3(
+            "upload_part",
+            s3.upload_part()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .body(buffer.into())
+                .part_number(number)
+                .send(),
+        )
+        .await?;
 
         let part = CompletedPart::builder()
             .e_tag(upload.e_tag.unwrap())
@@ -137,41 +141,97 @@ where
 {
     let span = Span::current();
 
-    let create_multipart = s3
-        .create_multipart_upload()
-        .bucket(bucket)
-        .key(key)
-        .send()
-        .await?;
+    let create_multipart = metrics::s3(
+        "create_multipart_upload",
+        s3.create_multipart_upload().bucket(bucket).key(key).send(),
+    )
+    .await?;
 
     let upload_id = create_multipart.upload_id().unwrap();
 
     span.record("upload", &upload_id[upload_id.len().saturating_sub(16)..]);
 
-    match multipart_upload_stream(s3, bucket, key, upload_id, source).await {
+    let mut guard = AbortGuard {
+        s3: s3.clone(),
+        bucket: bucket.to_owned(),
+        key: key.to_owned(),
+        upload_id: Some(upload_id.to_owned()),
+    };
+
+    let result = multipart_upload_stream(s3, bucket, key, upload_id, source).await;
+
+    // completed or aborted below
+    guard.upload_id = None;
+
+    match result {
         Ok((complete, upload)) => {
-            s3.complete_multipart_upload()
-                .bucket(bucket)
-                .key(key)
-                .multipart_upload(complete)
-                .upload_id(upload_id)
-                .send()
-                .await?;
+            metrics::s3(
+                "complete_multipart_upload",
+                s3.complete_multipart_upload()
+                    .bucket(bucket)
+                    .key(key)
+                    .multipart_upload(complete)
+                    .upload_id(upload_id)
+                    .send(),
+            )
+            .await?;
 
             debug!(hash = %upload.hash, length = upload.length, "upload complete");
 
             Ok(upload)
         }
         Err(error) => {
-            s3.abort_multipart_upload()
-                .bucket(bucket)
-                .key(key)
-                .upload_id(upload_id)
-                .send()
-                .await?;
+            metrics::s3(
+                "abort_multipart_upload",
+                s3.abort_multipart_upload()
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send(),
+            )
+            .await?;
 
             error!(%error, "upload error");
             Err(error)
         }
     }
 }
+
+// aborts the multipart upload if the request is dropped mid-way,
+// e.g. the client disconnects or the server shuts down
+struct AbortGuard {
+    s3: S3Client,
+    bucket: String,
+    key: String,
+    upload_id: Option<String>,
+}
+
+impl Drop for AbortGuard {
+    fn drop(&mut self) {
+        let Some(upload_id) = self.upload_id.take() else {
+            return;
+        };
+
+        let s3 = self.s3.clone();
+        let bucket = std::mem::take(&mut self.bucket);
+        let key = std::mem::take(&mut self.key);
+
+        warn!(key, "multipart upload interrupted, aborting");
+
+        tokio::spawn(async move {
+            let result = metrics::s3(
+                "abort_multipart_upload",
+                s3.abort_multipart_upload()
+                    .bucket(bucket)
+                    .key(key)
+                    .upload_id(upload_id)
+                    .send(),
+            )
+            .await;
+
+            if let Err(error) = result {
+                error!(%error, "failed to abort multipart upload");
+            }
+        });
+    }
+}
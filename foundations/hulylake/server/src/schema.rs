@@ -0,0 +1,261 @@
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+
+use actix_web::error::ErrorBadRequest;
+use bytes::Bytes;
+use lru::LruCache;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::*;
+use uuid::Uuid;
+
+use crate::handlers::{HandlerResult, PartData};
+use crate::merge::{self, MergeStrategy};
+use crate::postgres::{self, Pool};
+use crate::s3::S3Client;
+
+// schema for keys starting with a prefix is stored at the key SCHEMA_PREFIX + prefix
+pub const SCHEMA_PREFIX: &str = "_schema/";
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SchemaError {
+    #[error("invalid schema {schema}: {message}")]
+    InvalidSchema { schema: String, message: String },
+
+    #[error("objects covered by schema {schema} must use a json merge strategy")]
+    MergeStrategy { schema: String },
+
+    #[error("document does not match schema {schema}")]
+    Violation {
+        schema: String,
+        violations: Vec<Violation>,
+    },
+}
+
+pub struct Schema {
+    pub key: String,
+    validator: Arc<jsonschema::Validator>,
+}
+
+// compiled schemas kept in memory, a workspace usually has a few
+const VALIDATOR_CACHE_ENTRIES: usize = 256;
+
+/// Compiled schemas keyed by the etag of the schema object, a new version gets a new etag.
+pub struct Validators(Mutex<LruCache<String, Arc<jsonschema::Validator>>>);
+
+impl Validators {
+    pub fn new() -> Self {
+        let entries = NonZeroUsize::new(VALIDATOR_CACHE_ENTRIES).unwrap();
+        Self(Mutex::new(LruCache::new(entries)))
+    }
+}
+
+pub fn is_schema_key(key: &str) -> bool {
+    key.starts_with(SCHEMA_PREFIX)
+}
+
+pub fn is_json(merge_strategy: MergeStrategy) -> bool {
+    matches!(
+        merge_strategy,
+        MergeStrategy::JsonPatch | MergeStrategy::JsonMerge
+    )
+}
+
+// schema keys for every prefix of the key
+fn candidates(key: &str) -> Vec<String> {
+    postgres::prefixes(key)
+        .map(|prefix| format!("{SCHEMA_PREFIX}{prefix}"))
+        .collect()
+}
+
+/// Schema registered for the longest prefix of the key.
+#[instrument(level = "debug", skip_all)]
+pub async fn find(
+    pool: &Pool,
+    s3: Arc<S3Client>,
+    validators: &Validators,
+    workspace: Uuid,
+    key: &str,
+) -> HandlerResult<Option<Schema>> {
+    if is_schema_key(key) {
+        return Ok(None);
+    }
+
+    let Some(schema_key) = postgres::find_longest_key(pool, workspace, &candidates(key)).await?
+    else {
+        return Ok(None);
+    };
+
+    let parts = postgres::find_parts::<PartData>(pool, workspace, &schema_key).await?;
+    let Some(etag) = parts.last().map(|last| last.data.etag.clone()) else {
+        return Ok(None);
+    };
+
+    debug!(schema = schema_key, "found schema");
+
+    let cached = validators.0.lock().unwrap().get(&etag).cloned();
+    if let Some(validator) = cached {
+        return Ok(Some(Schema {
+            key: schema_key,
+            validator,
+        }));
+    }
+
+    let document = merge::project(s3, None, None, parts, "")
+        .await?
+        .unwrap_or_default();
+
+    let schema = Schema::new(schema_key, &document)?;
+    validators
+        .0
+        .lock()
+        .unwrap()
+        .put(etag, schema.validator.clone());
+
+    Ok(Some(schema))
+}
+
+/// Fails for objects which would bypass validation, a schema covers json objects only and a
+/// schema object cannot be appended to.
+pub async fn check_strategy(
+    pool: &Pool,
+    workspace: Uuid,
+    key: &str,
+    merge_strategy: MergeStrategy,
+) -> HandlerResult<()> {
+    if is_json(merge_strategy) {
+        return Ok(());
+    }
+
+    let schema = if is_schema_key(key) {
+        Some(key.to_owned())
+    } else {
+        postgres::find_longest_key(pool, workspace, &candidates(key)).await?
+    };
+
+    match schema {
+        Some(schema) => Err(SchemaError::MergeStrategy { schema }.into()),
+        None => Ok(()),
+    }
+}
+
+fn compile(key: &str, schema: &Value) -> Result<jsonschema::Validator, SchemaError> {
+    jsonschema::validator_for(schema).map_err(|error| SchemaError::InvalidSchema {
+        schema: key.to_owned(),
+        message: error.to_string(),
+    })
+}
+
+impl Schema {
+    pub fn new(key: String, document: &Value) -> Result<Self, SchemaError> {
+        let validator = Arc::new(compile(&key, document)?);
+        Ok(Self { key, validator })
+    }
+
+    pub fn validate(&self, document: &Value) -> Result<(), SchemaError> {
+        let violations = self
+            .validator
+            .iter_errors(document)
+            .map(|error| Violation {
+                path: error.instance_path.to_string(),
+                message: error.to_string(),
+            })
+            .collect::<Vec<_>>();
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(SchemaError::Violation {
+                schema: self.key.clone(),
+                violations,
+            })
+        }
+    }
+}
+
+/// Checks a document written to the key, a schema object must itself be a valid schema.
+pub fn validate(key: &str, schema: Option<&Schema>, document: &Value) -> Result<(), SchemaError> {
+    if is_schema_key(key) {
+        compile(key, document)?;
+        return Ok(());
+    }
+
+    match schema {
+        Some(schema) => schema.validate(document),
+        None => Ok(()),
+    }
+}
+
+/// Checks the body of a json object or a schema put to the key.
+pub async fn validate_put(
+    pool: &Pool,
+    s3: Arc<S3Client>,
+    validators: &Validators,
+    workspace: Uuid,
+    key: &str,
+    body: &Bytes,
+) -> HandlerResult<()> {
+    let schema = find(pool, s3, validators, workspace, key).await?;
+    if schema.is_none() && !is_schema_key(key) {
+        return Ok(());
+    }
+
+    let document =
+        serde_json::from_slice::<Value>(body).map_err(|e| ErrorBadRequest(e.to_string()))?;
+
+    Ok(validate(key, schema.as_ref(), &document)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_candidates() {
+        assert_eq!(
+            candidates("a/b"),
+            vec!["_schema/", "_schema/a", "_schema/a/", "_schema/a/b"]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let schema = Schema::new(
+            "_schema/config/".to_string(),
+            &json!({
+                "type": "object",
+                "properties": { "theme": { "enum": ["dark", "light"] } },
+                "required": ["theme"]
+            }),
+        )
+        .unwrap();
+
+        assert!(validate("config/ui", Some(&schema), &json!({ "theme": "dark" })).is_ok());
+        assert!(validate("config/ui", None, &json!({ "theme": 1 })).is_ok());
+
+        match validate("config/ui", Some(&schema), &json!({ "theme": "blue" })) {
+            Err(SchemaError::Violation { schema, violations }) => {
+                assert_eq!(schema, "_schema/config/");
+                assert_eq!(violations.len(), 1);
+                assert_eq!(violations[0].path, "/theme");
+            }
+            res => panic!("unexpected result: {res:?}"),
+        }
+    }
+
+    #[test]
+    fn test_validate_schema_key() {
+        assert!(validate("_schema/config/", None, &json!({ "type": "object" })).is_ok());
+        assert!(matches!(
+            validate("_schema/config/", None, &json!({ "type": 1 })),
+            Err(SchemaError::InvalidSchema { .. })
+        ));
+    }
+}
@@ -0,0 +1,55 @@
+use tanu::{check, check_eq, eyre, http::Client};
+
+use crate::util::*;
+
+#[tanu::test]
+pub async fn audit_writer() -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    let res = http
+        .key_put(&key)
+        .body(random_text(10))
+        .header("content-type", "text/plain")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = http
+        .key_patch(&key)
+        .body(random_text(10))
+        .header("content-type", "text/plain")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = http.key_get(&format!("{key}?meta")).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let meta = res.json::<serde_json::Value>().await?;
+    let parts = meta["parts"].as_array().unwrap();
+    check_eq!(2, parts.len());
+    check!(parts.iter().all(|part| part["writer"].is_string()));
+
+    let res = http.key_delete(&key).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = http.key_get(&format!("_audit?key={key}")).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let audit = res.json::<serde_json::Value>().await?;
+    let actions = audit["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["action"].as_str().unwrap().to_owned())
+        .collect::<Vec<_>>();
+
+    // newest first
+    check_eq!(vec!["delete", "patch", "put"], actions);
+    check_eq!(parts[1]["writer"], audit["entries"][0]["writer"]);
+    check_eq!(parts[0]["etag"], audit["entries"][2]["etag"]);
+
+    Ok(())
+}
@@ -4,7 +4,7 @@ use tanu::{
     http::{Client, Method, StatusCode},
 };
 
-use crate::config::CONFIG;
+use crate::config::{CONFIG, SCOPED_PREFIX};
 use crate::util::*;
 
 // #[tanu::test("HEAD", Method::HEAD)]
@@ -39,3 +39,66 @@ async fn auth_with_token(_: &str, method: Method) -> eyre::Result<()> {
 
     Ok(())
 }
+
+#[tanu::test]
+async fn auth_read_only_token() -> eyre::Result<()> {
+    let http = Client::new();
+    let key = random_key();
+
+    let res = http.key_put(&key).body("text").send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = http
+        .put(path(&key))
+        .bearer_auth(CONFIG.token_read.expose_secret())
+        .body("text")
+        .send()
+        .await?;
+    check_eq!(StatusCode::FORBIDDEN, res.status());
+
+    let res = http
+        .patch(path(&key))
+        .bearer_auth(CONFIG.token_read.expose_secret())
+        .body("text")
+        .send()
+        .await?;
+    check_eq!(StatusCode::FORBIDDEN, res.status());
+
+    let res = http
+        .get(path(&key))
+        .bearer_auth(CONFIG.token_read.expose_secret())
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    Ok(())
+}
+
+#[tanu::test]
+async fn auth_scoped_token() -> eyre::Result<()> {
+    let http = Client::new();
+
+    let res = http
+        .put(path(&format!("{SCOPED_PREFIX}{}", random_key())))
+        .bearer_auth(CONFIG.token_scoped.expose_secret())
+        .body("text")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let key = random_key();
+
+    let res = http.key_put(&key).body("text").send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    for method in [Method::GET, Method::PUT, Method::DELETE] {
+        let res = http
+            .request(&method, &path(&key))
+            .bearer_auth(CONFIG.token_scoped.expose_secret())
+            .send()
+            .await?;
+        check_eq!(StatusCode::FORBIDDEN, res.status(), "method: {method}");
+    }
+
+    Ok(())
+}
@@ -1,5 +1,8 @@
 use serde_json::{self as json, Value, json};
-use tanu::{check, eyre, http::Client};
+use tanu::{
+    check, check_eq, eyre,
+    http::{self, Client},
+};
 
 use crate::util::*;
 
@@ -200,3 +203,232 @@ pub async fn compact_get_json(count: usize) -> eyre::Result<()> {
 
     Ok(())
 }
+
+#[tanu::test(10)]
+#[tanu::test(150)]
+pub async fn compact_tail_text(count: usize) -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    let res = http
+        .key_put(&key)
+        .body("0;")
+        .header("content-type", "text/plain")
+        .send()
+        .await?;
+
+    check!(res.status().is_success(), "{:#?}", res);
+
+    for i in 1..=count {
+        let res = http
+            .key_patch(&key)
+            .body(format!("{i};"))
+            .header("content-type", "text/plain")
+            .send()
+            .await?;
+
+        check!(res.status().is_success(), "{:#?}", res);
+    }
+
+    // trigger compaction if there are enough parts
+    let res = http.key_get(&key).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+
+    let after = count - 3;
+    let expected = ((after + 1)..=count)
+        .map(|i| format!("{i};"))
+        .collect::<String>();
+
+    let res = http
+        .key_get(&format!("{key}?after-part={after}"))
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+    check_eq!(
+        res.header("huly-last-part"),
+        Some(count.to_string().as_str())
+    );
+
+    let total = res
+        .header("huly-total-length")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or_default();
+
+    check_eq!(res.text().await?, expected);
+
+    // the same by byte offset
+    let res = http
+        .key_get(&key)
+        .header("huly-since", (total - expected.len()).to_string())
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+    check_eq!(res.text().await?, expected);
+
+    // nothing new
+    let res = http
+        .key_get(&format!("{key}?after-part={count}"))
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+    check_eq!(res.text().await?, "");
+
+    Ok(())
+}
+
+#[tanu::test(10)]
+#[tanu::test(150)]
+pub async fn compact_part_text(count: usize) -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    let res = http
+        .key_put(&key)
+        .body("0;")
+        .header("content-type", "text/plain")
+        .send()
+        .await?;
+
+    check!(res.status().is_success(), "{:#?}", res);
+
+    for i in 1..=count {
+        let res = http
+            .key_patch(&key)
+            .body(format!("{i};"))
+            .header("content-type", "text/plain")
+            .send()
+            .await?;
+
+        check!(res.status().is_success(), "{:#?}", res);
+    }
+
+    // trigger compaction if there are enough parts
+    let res = http.key_get(&key).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+
+    // the listing holds every part with its offset
+    let res = http.key_get(&format!("{key}?meta")).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let meta = res.json::<Value>().await?;
+    let parts = meta["parts"].as_array().unwrap();
+    check_eq!(count + 1, parts.len());
+
+    let part = count / 2;
+    let offset = (0..part).map(|i| format!("{i};").len()).sum::<usize>();
+    check_eq!(parts[part]["part"], part);
+    check_eq!(parts[part]["offset"], offset);
+
+    let etag = format!("\"{}\"", parts[part]["etag"].as_str().unwrap());
+
+    let res = http.key_get(&format!("{key}?part={part}")).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+    check_eq!(res.header("etag"), Some(etag.as_str()));
+    check_eq!(res.header("huly-part"), Some(part.to_string().as_str()));
+    check_eq!(res.text().await?, format!("{part};"));
+
+    // not modified by its own etag
+    let res = http
+        .key_get(&format!("{key}?part={part}"))
+        .header("if-none-match", etag.as_str())
+        .send()
+        .await?;
+    check_eq!(http::StatusCode::NOT_MODIFIED, res.status());
+
+    let res = http
+        .key_get(&format!("{key}?part={}", count + 1))
+        .send()
+        .await?;
+    check_eq!(http::StatusCode::NOT_FOUND, res.status());
+
+    Ok(())
+}
+
+#[tanu::test]
+pub async fn compact_large_json() -> eyre::Result<()> {
+    let key = random_key();
+    let text = random_text(3 * 1024 * 1024);
+    let http = Client::new();
+
+    let res = http
+        .key_put(&key)
+        .body(json::to_string(&json!({ "text": text, "a": [] }))?)
+        .header("huly-merge-strategy", "jsonpatch")
+        .header("content-type", "application/json")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    for i in 0..150 {
+        let patch = json!([{ "op": "add", "path": format!("/a/{i}"), "value": i }]);
+
+        let res = http
+            .key_patch(&key)
+            .body(json::to_string(&patch)?)
+            .header("content-type", "application/json-patch+json")
+            .send()
+            .await?;
+        check!(res.status().is_success(), "{:#?}", res);
+    }
+
+    // trigger compaction
+    let res = http.key_get(&key).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    tokio::time::sleep(std::time::Duration::from_millis(3000)).await;
+
+    let res = http.key_get(&format!("{key}?meta")).send().await?;
+    let meta = res.json::<Value>().await?;
+    check_eq!(1, meta["parts"].as_array().unwrap().len());
+
+    let res = http.key_get(&key).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+    check_eq!(
+        json!({ "text": text, "a": (0..150).collect::<Vec<_>>() }),
+        res.json::<Value>().await?
+    );
+
+    Ok(())
+}
+
+#[tanu::test]
+pub async fn compact_on_patch() -> eyre::Result<()> {
+    let key = random_key();
+    let http = Client::new();
+
+    let res = http
+        .key_put(&key)
+        .body(json::to_string(&json!({ "a": [] }))?)
+        .header("huly-merge-strategy", "jsonpatch")
+        .header("content-type", "application/json")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    // the last patch reaches the default limit of 100 parts, nothing reads the object
+    for i in 0..99 {
+        let patch = json!([{ "op": "add", "path": format!("/a/{i}"), "value": i }]);
+
+        let res = http
+            .key_patch(&key)
+            .body(json::to_string(&patch)?)
+            .header("content-type", "application/json-patch+json")
+            .send()
+            .await?;
+        check!(res.status().is_success(), "{:#?}", res);
+    }
+
+    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+
+    let res = http.key_get(&format!("{key}?meta")).send().await?;
+    let meta = res.json::<Value>().await?;
+    check_eq!(1, meta["parts"].as_array().unwrap().len());
+
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use std::sync::LazyLock;
+use std::{collections::HashMap, sync::LazyLock};
 
 use hulyrs::services::jwt::ClaimsBuilder;
 use secrecy::SecretString;
@@ -9,8 +9,19 @@ pub struct Config {
     pub workspace: Uuid,
     pub token_valid: SecretString,
     pub token_invalid: SecretString,
+
+    // may only read
+    pub token_read: SecretString,
+
+    // may only access keys under SCOPED_PREFIX
+    pub token_scoped: SecretString,
+
+    // the server splits large blobs into content-defined chunks
+    pub chunking: bool,
 }
 
+pub const SCOPED_PREFIX: &str = "scoped/";
+
 pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
     let config = tanu::get_config();
 
@@ -32,13 +43,29 @@ pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
         .build()
         .unwrap();
 
+    let claims_extra = |name: &str, value: &str| {
+        ClaimsBuilder::default()
+            .workspace(workspace)
+            .account(account)
+            .extra(HashMap::from([(name.to_owned(), value.to_owned())]))
+            .build()
+            .unwrap()
+    };
+
     let token_valid = claims_valid.encode(&secret).unwrap();
     let token_invalid = claims_invalid.encode(&secret).unwrap();
+    let token_read = claims_extra("access", "read").encode(&secret).unwrap();
+    let token_scoped = claims_extra("prefix", SCOPED_PREFIX)
+        .encode(&secret)
+        .unwrap();
 
     Config {
         base_url: config.get_str("base_url").unwrap().to_string(),
         workspace,
         token_valid,
         token_invalid,
+        token_read,
+        token_scoped,
+        chunking: config.get_bool("chunking").unwrap_or_default(),
     }
 });
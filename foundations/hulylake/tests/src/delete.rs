@@ -0,0 +1,67 @@
+use tanu::{
+    check, check_eq, eyre,
+    http::{self, Client},
+};
+
+use crate::util::*;
+
+#[tanu::test]
+pub async fn delete_object() -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    let res = http
+        .key_put(&key)
+        .body(random_text(100))
+        .header("content-type", "text/plain")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    // stale etag
+    let res = http
+        .key_delete(&key)
+        .header("if-match", "\"stale\"")
+        .send()
+        .await?;
+    check_eq!(http::StatusCode::PRECONDITION_FAILED, res.status());
+
+    let res = http.key_delete(&key).send().await?;
+    check_eq!(http::StatusCode::NO_CONTENT, res.status());
+
+    let res = http.key_get(&key).send().await?;
+    check_eq!(http::StatusCode::NOT_FOUND, res.status());
+
+    let res = http.key_delete(&key).send().await?;
+    check_eq!(http::StatusCode::NOT_FOUND, res.status());
+
+    Ok(())
+}
+
+#[tanu::test]
+pub async fn put_expires() -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    let res = http
+        .key_put(&key)
+        .body(random_text(100))
+        .header("content-type", "text/plain")
+        .header("huly-expires", "tomorrow")
+        .send()
+        .await?;
+    check_eq!(http::StatusCode::BAD_REQUEST, res.status());
+
+    let res = http
+        .key_put(&key)
+        .body(random_text(100))
+        .header("content-type", "text/plain")
+        .header("huly-expires", "Wed, 02 Jan 2030 03:04:05 GMT")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    Ok(())
+}
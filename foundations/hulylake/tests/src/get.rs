@@ -3,6 +3,7 @@ use tanu::{
     http::{self, Client},
 };
 
+use crate::config::CONFIG;
 use crate::util::*;
 
 #[tanu::test]
@@ -120,6 +121,59 @@ pub async fn get_partial() -> eyre::Result<()> {
     Ok(())
 }
 
+#[tanu::test]
+pub async fn get_partial_chunked() -> eyre::Result<()> {
+    let key = random_key();
+    let length = 12 * 1024 * 1024;
+    let text = random_text(length);
+
+    let http = Client::new();
+
+    // above the multipart threshold, so the server splits it into chunks if chunking is enabled
+    let res = http
+        .key_put(&key)
+        .header("content-type", "text/plain")
+        .body(text.clone())
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    if CONFIG.chunking {
+        let chunks = res
+            .header("huly-chunks-count")
+            .and_then(|count| count.parse::<usize>().ok())
+            .unwrap_or_default();
+        check!(chunks > 1, "chunks: {chunks}");
+    }
+
+    // ranges across chunk boundaries, wherever the content puts them
+    let mut ranges = vec![(100, length - 101)];
+    ranges.extend(
+        (0..length - 300_000)
+            .step_by(700_000)
+            .map(|start| (start, start + 299_999)),
+    );
+
+    for (start, end) in ranges {
+        let res = http
+            .key_get(&key)
+            .header("range", format!("bytes={start}-{end}"))
+            .send()
+            .await?;
+        check_eq!(res.status(), http::StatusCode::PARTIAL_CONTENT);
+        check_eq!(
+            res.header("content-range"),
+            Some(format!("bytes {start}-{end}/{length}").as_str())
+        );
+        check!(
+            res.text().await? == text[start..=end],
+            "range {start}-{end}"
+        );
+    }
+
+    Ok(())
+}
+
 #[tanu::test]
 pub async fn get_partial_inline() -> eyre::Result<()> {
     let key = random_key();
@@ -154,3 +208,71 @@ pub async fn get_partial_inline() -> eyre::Result<()> {
 
     Ok(())
 }
+
+#[tanu::test("jsonpatch", "application/json")]
+#[tanu::test("concatenate", "text/plain")]
+pub async fn get_pointer(merge_strategy: &str, content_type: &str) -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    let document = serde_json::json!({
+        "settings": { "theme": "dark", "langs": ["en", "ru"] }
+    });
+
+    let res = http
+        .key_put(&key)
+        .body(document.to_string())
+        .header("huly-merge-strategy", merge_strategy)
+        .header("content-type", content_type)
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = http
+        .key_get(&format!("{key}?pointer=/settings/theme"))
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+    check_eq!(Some("application/json"), res.header("content-type"));
+    check_eq!(
+        serde_json::json!("dark"),
+        res.json::<serde_json::Value>().await?
+    );
+
+    let res = http
+        .key_get(&format!("{key}?pointer=/settings/langs/1"))
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+    check_eq!(
+        serde_json::json!("ru"),
+        res.json::<serde_json::Value>().await?
+    );
+
+    let res = http
+        .key_get(&format!("{key}?pointer=/settings/missing"))
+        .send()
+        .await?;
+    check_eq!(http::StatusCode::NOT_FOUND, res.status());
+
+    Ok(())
+}
+
+#[tanu::test]
+pub async fn get_pointer_too_large() -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    // beyond the json size limit, the document would be read into memory
+    let document = serde_json::json!({ "a": random_text(11 * 1024 * 1024) });
+
+    let res = http.key_put(&key).body(document.to_string()).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = http.key_get(&format!("{key}?pointer=/a")).send().await?;
+    check_eq!(http::StatusCode::PAYLOAD_TOO_LARGE, res.status());
+
+    Ok(())
+}
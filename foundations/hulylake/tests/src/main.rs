@@ -1,11 +1,17 @@
+mod audit;
 mod auth;
 mod compact;
 mod config;
+mod delete;
 mod get;
 mod head;
+mod meta;
 mod patch;
 mod put;
+mod query;
+mod retention;
 mod sanity;
+mod schema;
 mod util;
 
 use tanu::eyre;
@@ -0,0 +1,88 @@
+use tanu::{
+    check, check_eq, eyre,
+    http::{self, Client},
+};
+
+use crate::util::*;
+
+#[tanu::test]
+pub async fn meta_read_update() -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    let res = http
+        .key_put(&key)
+        .body(random_text(1000))
+        .header("content-type", "text/plain")
+        .header(
+            "huly-header-content-disposition",
+            "attachment; filename=\"a.txt\"",
+        )
+        .header("huly-meta-owner", "alice")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = http.key_get(&format!("{key}?meta")).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+    let etag = res.header("etag").unwrap().to_owned();
+
+    let meta = res.json::<serde_json::Value>().await?;
+    check_eq!(serde_json::json!("concatenate"), meta["merge_strategy"]);
+    check_eq!(serde_json::json!(1000), meta["size"]);
+    check_eq!(serde_json::json!("alice"), meta["meta"]["owner"]);
+    check_eq!(
+        serde_json::json!("attachment; filename=\"a.txt\""),
+        meta["headers"]["content-disposition"]
+    );
+    check_eq!(1, meta["parts"].as_array().unwrap().len());
+
+    let update = serde_json::json!({
+        "headers": { "content-disposition": "attachment; filename=\"b.txt\"" },
+        "meta": { "owner": null }
+    });
+
+    // if-match is required
+    let res = http
+        .key_patch(&format!("{key}?meta"))
+        .header("content-type", "application/json")
+        .body(update.to_string())
+        .send()
+        .await?;
+    check_eq!(http::StatusCode::PRECONDITION_REQUIRED, res.status());
+
+    let res = http
+        .key_patch(&format!("{key}?meta"))
+        .header("content-type", "application/json")
+        .header("if-match", &etag)
+        .body(update.to_string())
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+    check!(res.header("etag") != Some(etag.as_str()));
+
+    // stale etag
+    let res = http
+        .key_patch(&format!("{key}?meta"))
+        .header("content-type", "application/json")
+        .header("if-match", &etag)
+        .body(update.to_string())
+        .send()
+        .await?;
+    check_eq!(http::StatusCode::PRECONDITION_FAILED, res.status());
+
+    let res = http.key_head(&key).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+    check_eq!(
+        Some("attachment; filename=\"b.txt\""),
+        res.header("content-disposition")
+    );
+    check_eq!(Some("1000"), res.header("content-length"));
+
+    let res = http.key_get(&format!("{key}?meta")).send().await?;
+    let meta = res.json::<serde_json::Value>().await?;
+    check!(meta["meta"].get("owner").is_none());
+
+    Ok(())
+}
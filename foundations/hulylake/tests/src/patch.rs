@@ -144,6 +144,166 @@ async fn get_json_patch() -> eyre::Result<()> {
     Ok(())
 }
 
+#[tanu::test]
+async fn get_json_patch_set_ops() -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    let initial = json!({
+        "seen": 10,
+        "first": 10,
+        "score": 3,
+        "members": ["a", "b"]
+    });
+
+    let res = http
+        .key_put(&key)
+        .body(json::to_string(&initial)?)
+        .header("huly-merge-strategy", "jsonpatch")
+        .header("content-type", "application/json")
+        .send()
+        .await?;
+
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let patch = json!([
+        { "hop": "max", "path": "/seen", "value": 20 },
+        { "hop": "max", "path": "/seen", "value": 15 },
+        { "hop": "min", "path": "/first", "value": 5 },
+        { "hop": "mul", "path": "/score", "value": 2 },
+        { "hop": "push", "path": "/members", "value": "c" },
+        { "hop": "push", "path": "/members", "value": "a" },
+        { "hop": "pull", "path": "/members", "value": "b" },
+        { "hop": "pull", "path": "/missing", "value": "b", "safe": true }
+    ]);
+
+    let res = http
+        .key_patch(&key)
+        .body(json::to_string(&patch)?)
+        .header("content-type", "application/json-patch+json")
+        .send()
+        .await?;
+
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = http.key_get(&key).send().await?;
+
+    let json = res.json::<Value>().await?;
+
+    assert_eq!(
+        json,
+        json!({ "seen": 20, "first": 5, "score": 6, "members": ["a", "c"] })
+    );
+
+    Ok(())
+}
+
+#[tanu::test]
+async fn patch_json_patch_rejected() -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    let res = http
+        .key_put(&key)
+        .body(json::to_string(&json!({ "version": 1 }))?)
+        .header("huly-merge-strategy", "jsonpatch")
+        .header("content-type", "application/json")
+        .send()
+        .await?;
+
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let patch = |ops: Value| {
+        http.key_patch(&key)
+            .body(ops.to_string())
+            .header("content-type", "application/json-patch+json")
+            .send()
+    };
+
+    // test operation gives optimistic concurrency
+    let res = patch(json!([
+        { "op": "test", "path": "/version", "value": 1 },
+        { "op": "replace", "path": "/version", "value": 2 }
+    ]))
+    .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = patch(json!([
+        { "op": "test", "path": "/version", "value": 1 },
+        { "op": "replace", "path": "/version", "value": 3 }
+    ]))
+    .await?;
+    check_eq!(res.status(), StatusCode::CONFLICT, "{:#?}", res);
+
+    // operation that cannot apply
+    let res = patch(json!([{ "op": "add", "path": "/a/b", "value": 1 }])).await?;
+    check_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{:#?}", res);
+    check!(res.json::<Value>().await?["error"].is_string());
+
+    let res = http.key_get(&key).send().await?;
+    assert_eq!(res.json::<Value>().await?, json!({ "version": 2 }));
+
+    Ok(())
+}
+
+#[tanu::test]
+async fn get_json_merge() -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    let initial = json!({
+        "a": 1,
+        "b": { "c": 2, "d": 3 },
+        "e": [1, 2]
+    });
+
+    // create new blob
+    let res = http
+        .key_put(&key)
+        .body(json::to_string(&initial)?)
+        .header("huly-merge-strategy", "jsonmerge")
+        .header("content-type", "application/json")
+        .send()
+        .await?;
+
+    check!(res.status().is_success(), "{:#?}", res);
+
+    // json patch body is rejected
+    let res = http
+        .key_patch(&key)
+        .body("[]")
+        .header("content-type", "application/json-patch+json")
+        .send()
+        .await?;
+
+    check_eq!(res.status(), StatusCode::BAD_REQUEST, "{:#?}", res);
+
+    for patch in [
+        json!({ "a": 4, "b": { "c": null } }),
+        json!({ "e": [3], "f": "g" }),
+    ] {
+        let res = http
+            .key_patch(&key)
+            .body(json::to_string(&patch)?)
+            .header("content-type", "application/merge-patch+json")
+            .send()
+            .await?;
+
+        check!(res.status().is_success(), "{:#?}", res);
+    }
+
+    let res = http.key_get(&key).send().await?;
+
+    let json = res.json::<Value>().await?;
+
+    assert_eq!(json, json!({ "a": 4, "b": { "d": 3 }, "e": [3], "f": "g" }));
+
+    Ok(())
+}
+
 #[tanu::test]
 async fn get_json_patch_unsafe() -> eyre::Result<()> {
     let key = random_key();
@@ -391,3 +551,264 @@ pub async fn put_and_patch_concurrent(count: usize) -> eyre::Result<()> {
 
     Ok(())
 }
+
+#[tanu::test]
+async fn truncate_json_patch() -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    let res = http
+        .key_put(&key)
+        .body(json::to_string(&json!({ "a": 1 }))?)
+        .header("huly-merge-strategy", "jsonpatch")
+        .header("content-type", "application/json")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+    let first = res.header("etag").unwrap().to_owned();
+
+    for value in [2, 3] {
+        let res = http
+            .key_patch(&key)
+            .body(json::to_string(
+                &json!([{ "op": "replace", "path": "/a", "value": value }]),
+            )?)
+            .header("content-type", "application/json-patch+json")
+            .send()
+            .await?;
+        check!(res.status().is_success(), "{:#?}", res);
+    }
+
+    let res = http.key_head(&key).send().await?;
+    let etag = res.header("etag").unwrap().to_owned();
+
+    // if-match is required
+    let res = http.key_patch(&format!("{key}?truncate=1")).send().await?;
+    check_eq!(StatusCode::PRECONDITION_REQUIRED, res.status());
+
+    let res = http
+        .key_patch(&format!("{key}?truncate=7"))
+        .header("if-match", &etag)
+        .send()
+        .await?;
+    check_eq!(StatusCode::BAD_REQUEST, res.status());
+
+    let res = http
+        .key_patch(&format!("{key}?truncate=1"))
+        .header("if-match", &etag)
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let meta = res.json::<Value>().await?;
+    check_eq!(2, meta["parts"].as_array().unwrap().len());
+
+    let res = http.key_get(&key).send().await?;
+    let etag = res.header("etag").unwrap().to_owned();
+    check_eq!(json!({ "a": 2 }), res.json::<Value>().await?);
+
+    // back to the first part by its etag
+    let res = http
+        .key_patch(&format!("{key}?truncate={}", first.trim_matches('"')))
+        .header("if-match", &etag)
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = http.key_get(&key).send().await?;
+    check_eq!(json!({ "a": 1 }), res.json::<Value>().await?);
+
+    Ok(())
+}
+
+#[tanu::test]
+async fn truncate_compacted() -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    let res = http
+        .key_put(&key)
+        .body("0;")
+        .header("content-type", "text/plain")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    for i in 1..=150 {
+        let res = http
+            .key_patch(&key)
+            .body(format!("{i};"))
+            .header("content-type", "text/plain")
+            .send()
+            .await?;
+        check!(res.status().is_success(), "{:#?}", res);
+    }
+
+    // trigger compaction
+    let res = http.key_get(&key).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+
+    let res = http.key_get(&format!("{key}?meta")).send().await?;
+    let etag = res.header("etag").unwrap().to_owned();
+    let meta = res.json::<Value>().await?;
+    let parts = meta["parts"].as_array().unwrap();
+    check_eq!(151, parts.len());
+
+    // a part inside the compacted one, by the etag listed in the metadata
+    let target = parts[100]["etag"].as_str().unwrap();
+    let res = http
+        .key_patch(&format!("{key}?truncate={target}"))
+        .header("if-match", &etag)
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let meta = res.json::<Value>().await?;
+    check_eq!(101, meta["parts"].as_array().unwrap().len());
+
+    let expected = (0..=100).map(|i| format!("{i};")).collect::<String>();
+    let res = http.key_get(&key).send().await?;
+    let etag = res.header("etag").unwrap().to_owned();
+    check_eq!(expected, res.text().await?);
+
+    // and by its number
+    let res = http
+        .key_patch(&format!("{key}?truncate=10"))
+        .header("if-match", &etag)
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let expected = (0..=10).map(|i| format!("{i};")).collect::<String>();
+    let res = http.key_get(&key).send().await?;
+    check_eq!(expected, res.text().await?);
+
+    // appending continues after the retained parts
+    let res = http
+        .key_patch(&key)
+        .body("11;")
+        .header("content-type", "text/plain")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = http.key_get(&format!("{key}?part=11")).send().await?;
+    check_eq!("11;", res.text().await?);
+
+    Ok(())
+}
+
+#[tanu::test]
+async fn large_json_patch() -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    // larger than the inline threshold, stored in s3
+    let text = random_text(300 * 1024);
+
+    let res = http
+        .key_put(&key)
+        .body(json::to_string(&json!({ "a": text }))?)
+        .header("huly-merge-strategy", "jsonpatch")
+        .header("content-type", "application/json")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = http
+        .key_patch(&key)
+        .body(json::to_string(
+            &json!([{ "op": "add", "path": "/b", "value": text }]),
+        )?)
+        .header("content-type", "application/json-patch+json")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = http.key_get(&key).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+    check_eq!(json!({ "a": text, "b": text }), res.json::<Value>().await?);
+
+    Ok(())
+}
+
+#[tanu::test]
+async fn huge_json_patch() -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    // several megabytes, beyond what the memory cache keeps
+    let text = random_text(4 * 1024 * 1024);
+
+    let res = http
+        .key_put(&key)
+        .body(json::to_string(&json!({ "a": text }))?)
+        .header("huly-merge-strategy", "jsonpatch")
+        .header("content-type", "application/json")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    for value in [1, 2] {
+        let res = http
+            .key_patch(&key)
+            .body(json::to_string(
+                &json!([{ "op": "add", "path": "/b", "value": value }]),
+            )?)
+            .header("content-type", "application/json-patch+json")
+            .send()
+            .await?;
+        check!(res.status().is_success(), "{:#?}", res);
+
+        // read twice, the second read may come from the cache
+        for _ in 0..2 {
+            let res = http.key_get(&key).send().await?;
+            check!(res.status().is_success(), "{:#?}", res);
+            check_eq!(json!({ "a": text, "b": value }), res.json::<Value>().await?);
+        }
+    }
+
+    Ok(())
+}
+
+#[tanu::test]
+async fn json_patch_too_large() -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    // more than half of the json size limit
+    let text = random_text(6 * 1024 * 1024);
+
+    let res = http
+        .key_put(&key)
+        .body(json::to_string(&json!({ "a": text }))?)
+        .header("huly-merge-strategy", "jsonpatch")
+        .header("content-type", "application/json")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    // a small patch doubling the document
+    let res = http
+        .key_patch(&key)
+        .body(json::to_string(
+            &json!([{ "op": "copy", "from": "/a", "path": "/b" }]),
+        )?)
+        .header("content-type", "application/json-patch+json")
+        .send()
+        .await?;
+    check_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE, "{:#?}", res);
+
+    let res = http.key_get(&key).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+    check_eq!(json!({ "a": text }), res.json::<Value>().await?);
+
+    Ok(())
+}
@@ -412,3 +412,56 @@ pub async fn put_conditional_update(
 
     Ok(())
 }
+
+#[tanu::test(1024)]
+#[tanu::test(1 * 1024 * 1024)]
+#[tanu::test(10 * 1024 * 1024)]
+pub async fn put_chunked(length: usize) -> eyre::Result<()> {
+    let key = random_key();
+    let text = random_text(length);
+
+    // no content length, the body is sent with chunked transfer encoding
+    let chunks = text
+        .as_bytes()
+        .chunks(64 * 1024)
+        .map(|chunk| Ok::<_, std::io::Error>(chunk.to_vec()))
+        .collect::<Vec<_>>();
+    let body = reqwest::Body::wrap_stream(futures::stream::iter(chunks));
+
+    let http = Client::new();
+
+    let res = http
+        .key_put(&key)
+        .header("content-type", "text/plain")
+        .body(body)
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = http.key_get(&key).send().await?;
+    check!(res.status().is_success());
+    check_eq!(
+        Some(length.to_string().as_str()),
+        res.header("content-length")
+    );
+    check_eq!(text, res.text().await?);
+
+    Ok(())
+}
+
+#[tanu::test]
+pub async fn put_endpoint_key() -> eyre::Result<()> {
+    let http = Client::new();
+
+    for key in ["_audit", "_query"] {
+        let res = http
+            .key_put(key)
+            .body(random_text(10))
+            .header("content-type", "text/plain")
+            .send()
+            .await?;
+        check_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use tanu::{check, check_eq, eyre, http::Client};
+
+use crate::util::*;
+
+#[tanu::test]
+pub async fn query_meta() -> eyre::Result<()> {
+    let prefix = format!("{}/", random_key());
+
+    let http = Client::new();
+
+    for (name, kind) in [
+        ("a", "avatar"),
+        ("b", "attachment"),
+        ("c", "avatar"),
+        ("d", "avatar"),
+    ] {
+        let res = http
+            .key_put(&format!("{prefix}{name}"))
+            .body(random_text(10))
+            .header("content-type", "text/plain")
+            .header("huly-meta-kind", kind)
+            .send()
+            .await?;
+        check!(res.status().is_success(), "{:#?}", res);
+    }
+
+    let res = http
+        .key_get(&format!("_query?meta.kind=avatar&prefix={prefix}&limit=2"))
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let page = res.json::<serde_json::Value>().await?;
+    let keys = |page: &serde_json::Value| {
+        page["objects"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|object| object["key"].as_str().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+    check_eq!(
+        vec![format!("{prefix}a"), format!("{prefix}c")],
+        keys(&page)
+    );
+    check_eq!(
+        serde_json::json!("avatar"),
+        page["objects"][0]["meta"]["kind"]
+    );
+
+    let next = page["next"].as_str().unwrap();
+    let res = http
+        .key_get(&format!(
+            "_query?meta.kind=avatar&prefix={prefix}&limit=2&after={next}"
+        ))
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let page = res.json::<serde_json::Value>().await?;
+    check_eq!(vec![format!("{prefix}d")], keys(&page));
+    check!(page["next"].is_null());
+
+    Ok(())
+}
+
+#[tanu::test]
+pub async fn query_compacted() -> eyre::Result<()> {
+    let prefix = format!("{}/", random_key());
+    let key = format!("{prefix}log");
+
+    let http = Client::new();
+
+    let res = http
+        .key_put(&key)
+        .body("0;")
+        .header("content-type", "text/plain")
+        .header("huly-meta-kind", "log")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    for i in 1..=150 {
+        let res = http
+            .key_patch(&key)
+            .body(format!("{i};"))
+            .header("content-type", "text/plain")
+            .send()
+            .await?;
+        check!(res.status().is_success(), "{:#?}", res);
+    }
+
+    // trigger compaction, the compacted part keeps the number of the last one
+    let res = http.key_get(&key).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+
+    let res = http
+        .key_get(&format!("_query?meta.kind=log&prefix={prefix}"))
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let page = res.json::<serde_json::Value>().await?;
+    check_eq!(serde_json::json!(key), page["objects"][0]["key"]);
+    check_eq!(1, page["objects"].as_array().unwrap().len());
+
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use secrecy::ExposeSecret;
+use tanu::{
+    check, check_eq, eyre,
+    http::{self, Client},
+};
+
+use crate::config::CONFIG;
+use crate::util::*;
+
+#[tanu::test]
+pub async fn immutable_key() -> eyre::Result<()> {
+    let key = random_key();
+
+    let http = Client::new();
+
+    let res = http
+        .key_put(&key)
+        .body(random_text(100))
+        .header("content-type", "text/plain")
+        .header("huly-immutable", "true")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let res = http
+        .key_put(&key)
+        .body(random_text(100))
+        .header("content-type", "text/plain")
+        .send()
+        .await?;
+    check_eq!(http::StatusCode::CONFLICT, res.status());
+
+    let res = http
+        .key_patch(&key)
+        .body(random_text(100))
+        .header("content-type", "text/plain")
+        .send()
+        .await?;
+    check_eq!(http::StatusCode::CONFLICT, res.status());
+
+    let res = http.key_delete(&key).send().await?;
+    check_eq!(http::StatusCode::FORBIDDEN, res.status());
+
+    let res = http.key_get(&key).send().await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    Ok(())
+}
+
+#[tanu::test]
+pub async fn retention_requires_system() -> eyre::Result<()> {
+    let http = Client::new();
+
+    let res = http
+        .put(format!(
+            "{}/api/{}/_retention",
+            CONFIG.base_url, CONFIG.workspace
+        ))
+        .bearer_auth(CONFIG.token_valid.expose_secret())
+        .header("content-type", "application/json")
+        .body(serde_json::json!({ "key": random_key(), "legal_hold": true }).to_string())
+        .send()
+        .await?;
+    check_eq!(http::StatusCode::FORBIDDEN, res.status());
+
+    Ok(())
+}
@@ -12,3 +12,48 @@ async fn status_is_ok() -> eyre::Result<()> {
     check_eq!("ok", res.text().await?);
     Ok(())
 }
+
+#[tanu::test]
+async fn metrics_are_exposed() -> eyre::Result<()> {
+    let http = Client::new();
+
+    // make sure at least one request is recorded
+    http.get(format!("{}/status", CONFIG.base_url))
+        .send()
+        .await?;
+
+    let res = http
+        .get(format!("{}/metrics", CONFIG.base_url))
+        .send()
+        .await?;
+    check!(res.status().is_success());
+    check!(res.text().await?.contains("hulylake_http_requests_total"));
+    Ok(())
+}
+
+#[tanu::test]
+async fn healthz_is_ok() -> eyre::Result<()> {
+    let http = Client::new();
+    let res = http
+        .get(format!("{}/healthz", CONFIG.base_url))
+        .send()
+        .await?;
+    check!(res.status().is_success());
+    Ok(())
+}
+
+#[tanu::test]
+async fn readyz_reports_dependencies() -> eyre::Result<()> {
+    let http = Client::new();
+    let res = http
+        .get(format!("{}/readyz", CONFIG.base_url))
+        .send()
+        .await?;
+    check!(res.status().is_success());
+
+    let report = res.json::<serde_json::Value>().await?;
+    check_eq!(Some(true), report["ready"].as_bool());
+    check_eq!(Some("ok"), report["postgres"]["status"].as_str());
+    check_eq!(Some("ok"), report["s3"]["status"].as_str());
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use hulyrs::StatusCode;
+use serde_json::{Value, json};
+use tanu::{check, check_eq, eyre, http::Client};
+
+use crate::util::*;
+
+#[tanu::test]
+pub async fn schema_enforced() -> eyre::Result<()> {
+    let prefix = format!("{}/", random_key());
+
+    let http = Client::new();
+
+    // not a valid schema
+    let res = http
+        .key_put(&format!("_schema/{prefix}"))
+        .body(json!({ "type": 1 }).to_string())
+        .header("huly-merge-strategy", "jsonpatch")
+        .header("content-type", "application/json")
+        .send()
+        .await?;
+    check_eq!(res.status(), StatusCode::BAD_REQUEST, "{:#?}", res);
+
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "theme": { "enum": ["dark", "light"] }
+        },
+        "required": ["theme"]
+    });
+
+    // schemas are json objects themselves
+    let res = http
+        .key_put(&format!("_schema/{prefix}"))
+        .body(schema.to_string())
+        .header("content-type", "application/json")
+        .send()
+        .await?;
+    check_eq!(res.status(), StatusCode::BAD_REQUEST, "{:#?}", res);
+
+    let res = http
+        .key_put(&format!("_schema/{prefix}"))
+        .body(schema.to_string())
+        .header("huly-merge-strategy", "jsonpatch")
+        .header("content-type", "application/json")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    let key = format!("{prefix}settings");
+
+    let put = |document: Value| {
+        http.key_put(&key)
+            .body(document.to_string())
+            .header("huly-merge-strategy", "jsonpatch")
+            .header("content-type", "application/json")
+            .send()
+    };
+
+    let res = put(json!({ "theme": "blue" })).await?;
+    check_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{:#?}", res);
+
+    let error = res.json::<Value>().await?;
+    check_eq!(error["schema"], json!(format!("_schema/{prefix}")));
+    check_eq!(error["violations"][0]["path"], json!("/theme"));
+
+    let res = put(json!({ "theme": "dark" })).await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    // patch that breaks the document
+    let res = http
+        .key_patch(&key)
+        .body(json!([{ "op": "remove", "path": "/theme" }]).to_string())
+        .header("content-type", "application/json-patch+json")
+        .send()
+        .await?;
+    check_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY, "{:#?}", res);
+
+    let res = http
+        .key_patch(&key)
+        .body(json!([{ "op": "replace", "path": "/theme", "value": "light" }]).to_string())
+        .header("content-type", "application/json-patch+json")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    // other strategies would bypass the schema
+    let res = http
+        .key_put(&format!("{prefix}raw"))
+        .body(json!({ "theme": "blue" }).to_string())
+        .send()
+        .await?;
+    check_eq!(res.status(), StatusCode::BAD_REQUEST, "{:#?}", res);
+
+    let res = http
+        .key_patch(&format!("_schema/{prefix}"))
+        .body("garbage")
+        .send()
+        .await?;
+    check_eq!(res.status(), StatusCode::BAD_REQUEST, "{:#?}", res);
+
+    // keys outside of the prefix are not affected
+    let res = http
+        .key_put(&random_key())
+        .body(json!({ "theme": "blue" }).to_string())
+        .header("huly-merge-strategy", "jsonpatch")
+        .header("content-type", "application/json")
+        .send()
+        .await?;
+    check!(res.status().is_success(), "{:#?}", res);
+
+    Ok(())
+}
@@ -50,7 +50,7 @@ impl ClientExt for Client {
     }
 
     fn key_delete(&self, key: &str) -> RequestBuilder {
-        self.get(path(key))
+        self.delete(path(key))
             .bearer_auth(CONFIG.token_valid.expose_secret())
     }
 }